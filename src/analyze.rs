@@ -0,0 +1,109 @@
+use crate::{
+    calibration::CalibrationConfig,
+    cli::AnalyzeArgs,
+    detector::{Analyser, DetectorConfig},
+    session::{print_clap, print_miss, print_noise, print_summary, AppState, Plap, Tick},
+    tiers::Tier,
+};
+use hound::{SampleFormat, WavReader};
+use std::time::Duration;
+
+/// Replays a WAV recording through the live detector.
+///
+/// The recording goes through the same `Analyser` and session as live input, so the counts match
+/// what a live session on the same audio would have produced.
+pub fn analyze(
+    args: &AnalyzeArgs,
+    detector_config: &DetectorConfig,
+    calibration_config: CalibrationConfig,
+    active_delay: Duration,
+    tiers: Vec<Tier>,
+    miss_message: Option<String>,
+) -> anyhow::Result<()> {
+    let mut reader = WavReader::open(&args.file)?;
+    let spec = reader.spec();
+
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    let channels = spec.channels as usize;
+    let length =
+        Duration::from_secs_f64((samples.len() / channels) as f64 / spec.sample_rate as f64);
+    let time_limit = match args.time_limit {
+        Some(m) => Duration::from_secs_f32(m * 60.0),
+        None => length,
+    };
+
+    let mut state = AppState::new(
+        time_limit,
+        true,
+        calibration_config,
+        active_delay,
+        tiers,
+        miss_message,
+        spec.sample_rate,
+    );
+    let mut analyser = Analyser::new(detector_config, spec.sample_rate, channels)?;
+
+    let mut frames = Vec::new();
+    analyser.process(&samples, |frame| frames.push(frame));
+
+    for frame in frames {
+        match state.tick(&frame) {
+            Tick::Idle | Tick::Calibrated(_) => {}
+            Tick::Clap {
+                tier,
+                event,
+                remaining,
+            } => print_clap(&state, &format_event(&event), tier, remaining),
+            Tick::Miss { event, remaining } => print_miss(&state, &format_event(&event), remaining),
+            Tick::CalibrationFailed(failure) => {
+                println!("Calibration failed: {failure}.");
+            }
+            Tick::Noise(noise) => {
+                print!("[{}] ", format_timestamp(state.time_at(frame.end)));
+                print_noise(noise);
+            }
+            Tick::TimesUp => {
+                println!("Times up!");
+                print_summary(&state);
+                return Ok(());
+            }
+        }
+    }
+
+    println!("End of recording at {}.", format_timestamp(length));
+    print_summary(&state);
+
+    Ok(())
+}
+
+/// Timestamp and measurements of a plap or miss, ahead of its feedback line.
+fn format_event(event: &Plap) -> String {
+    format!(
+        "[{} {:3.0}/100 {:6.1} dB {:3} ms attack {:4} ms long] ",
+        format_timestamp(event.at),
+        event.score,
+        event.peak_db,
+        event.attack.as_millis(),
+        event.duration.as_millis()
+    )
+}
+
+fn format_timestamp(t: Duration) -> String {
+    let millis = t.as_millis();
+    format!(
+        "{:02}:{:02}.{:03}",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
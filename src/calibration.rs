@@ -0,0 +1,478 @@
+use crate::{
+    detector::{DetectorConfig, Frame},
+    filter::FilterSpec,
+    history,
+    strategies::{DetectorKind, Loudness},
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, mem, time::Duration};
+
+const CALIBRATIONS_FILE: &str = "calibrations.json";
+
+/// How long a phase waits for the next plap before giving up.
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Plaps further than this many median absolute deviations from the median are thrown out.
+const OUTLIER_DEVIATIONS: f32 = 3.0;
+/// Smallest median absolute deviation used for outlier rejection, in dB, so that a run of nearly
+/// identical plaps doesn't turn every small difference into an outlier.
+const MIN_DEVIATION: f32 = 1.0;
+
+/// How many standard deviations below the mean hard level the hard threshold sits at least.
+const SPREAD_FACTOR: f32 = 2.0;
+
+/// How far above the mean hard level a plap has to be to score 100 at least, in dB, so that an
+/// average hard plap leaves room to go harder.
+const MIN_SCORE_HEADROOM: f32 = 6.0;
+
+/// How far below hard plaps soft plaps have to be, in dB.
+const MIN_SOFT_DIFFERENCE: f32 = 3.0;
+
+/// How far the noise floor can move from a saved calibration's before it's likely out of date, in
+/// dB.
+const STALE_BASELINE_DIFFERENCE: f32 = 6.0;
+
+pub struct CalibrationConfig {
+    /// Whether to measure soft plaps after the hard ones.
+    pub soft_phase: bool,
+    /// Plaps to collect in each phase.
+    pub claps: usize,
+    /// Fraction of the hard level above the noise floor an onset has to reach to count as hard,
+    /// without a soft phase.
+    pub tolerance: f32,
+    /// How far above the noise floor hard plaps have to be, in dB.
+    pub min_hard_margin: f32,
+    /// How long to wait after the last plap before finishing a phase.
+    pub complete_delay: Duration,
+}
+
+/// Levels measured by calibration.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Noise floor when calibration finished, in dB.
+    pub baseline: f32,
+    pub hard: Levels,
+    pub soft: Option<Levels>,
+}
+
+impl Calibration {
+    /// Level an onset has to reach to count as hard, given the current baseline and the
+    /// calibration tolerance.
+    pub fn hard_threshold(&self, baseline: f32, tolerance: f32) -> f32 {
+        let hard = self.hard;
+        match self.soft {
+            // As many standard deviations above the soft mean as below the hard one
+            Some(soft) if hard.spread + soft.spread > 0.0 => {
+                soft.mean + (hard.mean - soft.mean) * soft.spread / (hard.spread + soft.spread)
+            }
+            Some(soft) => (hard.mean + soft.mean) / 2.0,
+            None => {
+                let tolerance = (hard.mean - baseline) * (1.0 - tolerance);
+                hard.mean - tolerance.max(hard.spread * SPREAD_FACTOR)
+            }
+        }
+    }
+
+    /// Intensity of a plap peaking at `db`, from 0 at the noise floor to 100 as far above the mean
+    /// hard level as hard plaps spread, measured by how far above the noise floor each is.
+    pub fn score(&self, db: f32, baseline: f32) -> f32 {
+        let headroom = (self.hard.spread * SPREAD_FACTOR).max(MIN_SCORE_HEADROOM);
+        let full_margin = self.hard.mean + headroom - self.baseline;
+        (100.0 * (db - baseline) / full_margin).clamp(0.0, 100.0)
+    }
+}
+
+/// Distribution of the onset levels in one calibration phase, in dB.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Levels {
+    pub mean: f32,
+    /// Standard deviation.
+    pub spread: f32,
+    pub counted: usize,
+    pub rejected: usize,
+}
+
+impl Levels {
+    /// Summarises `levels` after throwing out outliers.
+    fn measure(mut levels: Vec<f32>) -> Self {
+        let middle = median(&mut levels);
+        let mut deviations: Vec<f32> = levels.iter().map(|l| (l - middle).abs()).collect();
+        let deviation = median(&mut deviations).max(MIN_DEVIATION);
+
+        let total = levels.len();
+        levels.retain(|l| (l - middle).abs() <= deviation * OUTLIER_DEVIATIONS);
+
+        let counted = levels.len();
+        let mean = levels.iter().sum::<f32>() / counted as f32;
+        let variance = levels.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / counted as f32;
+
+        Self {
+            mean,
+            spread: variance.sqrt(),
+            counted,
+            rejected: total - counted,
+        }
+    }
+}
+
+/// Sorts `values` and returns their median. `values` can't be empty.
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(f32::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Why a calibration attempt didn't produce a usable result.
+pub enum Failure {
+    TooFewPlaps { heard: usize, wanted: usize },
+    TooQuiet { margin: f32, needed: f32 },
+    SoftTooLoud { difference: f32 },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::TooFewPlaps { heard, wanted } => write!(
+                f,
+                "heard only {heard} of {wanted} plaps before {}s passed without one",
+                CALIBRATION_TIMEOUT.as_secs()
+            ),
+            Failure::TooQuiet { margin, needed } => write!(
+                f,
+                "hard plaps were only {margin:.1} dB above the noise floor, they need at least \
+                 {needed:.0} dB"
+            ),
+            Failure::SoftTooLoud { difference } => write!(
+                f,
+                "soft plaps were only {difference:.1} dB quieter than hard ones, they need to be \
+                 at least {MIN_SOFT_DIFFERENCE:.0} dB quieter"
+            ),
+        }
+    }
+}
+
+enum Phase {
+    Hard,
+    Soft { hard: Levels },
+}
+
+/// Collects the levels of the onsets in each phase, moving on once there are enough of them and
+/// none has come in for `complete_delay`.
+pub struct Calibrator {
+    soft_phase: bool,
+    claps: usize,
+    min_hard_margin: f32,
+    complete_delay: Duration,
+    phase: Phase,
+    levels: Vec<f32>,
+    last_onset_at: Duration,
+}
+
+impl Calibrator {
+    pub fn start(config: &CalibrationConfig, now: Duration) -> Self {
+        println!("Beginning calibration, plap HARD{}!", times(config.claps));
+
+        Self {
+            soft_phase: config.soft_phase,
+            claps: config.claps,
+            min_hard_margin: config.min_hard_margin,
+            complete_delay: config.complete_delay,
+            phase: Phase::Hard,
+            levels: Vec::with_capacity(config.claps),
+            last_onset_at: now,
+        }
+    }
+
+    /// Feeds in the latest frame, returning the result once calibration is done.
+    pub fn update(&mut self, frame: &Frame, now: Duration) -> Option<Result<Calibration, Failure>> {
+        if let Some(onset) = frame.onset {
+            self.levels.push(onset.loudness);
+            self.last_onset_at = now;
+            return None;
+        }
+
+        let waited = now.saturating_sub(self.last_onset_at);
+        if self.levels.len() < self.claps {
+            if waited > CALIBRATION_TIMEOUT {
+                return Some(Err(Failure::TooFewPlaps {
+                    heard: self.levels.len(),
+                    wanted: self.claps,
+                }));
+            }
+            return None;
+        }
+        if waited <= self.complete_delay {
+            return None;
+        }
+
+        let levels = Levels::measure(mem::take(&mut self.levels));
+
+        match self.phase {
+            Phase::Hard => {
+                print_levels("Hard", &levels, frame.loudness_floor);
+
+                let margin = levels.mean - frame.loudness_floor;
+                if margin < self.min_hard_margin {
+                    return Some(Err(Failure::TooQuiet {
+                        margin,
+                        needed: self.min_hard_margin,
+                    }));
+                }
+
+                if !self.soft_phase {
+                    return Some(Ok(Calibration {
+                        baseline: frame.loudness_floor,
+                        hard: levels,
+                        soft: None,
+                    }));
+                }
+
+                println!("Now plap softly{}!", times(self.claps));
+
+                self.phase = Phase::Soft { hard: levels };
+                self.last_onset_at = now;
+                None
+            }
+            Phase::Soft { hard } => {
+                print_levels("Soft", &levels, frame.loudness_floor);
+
+                let difference = hard.mean - levels.mean;
+                if difference < MIN_SOFT_DIFFERENCE {
+                    return Some(Err(Failure::SoftTooLoud { difference }));
+                }
+
+                Some(Ok(Calibration {
+                    baseline: frame.loudness_floor,
+                    hard,
+                    soft: Some(levels),
+                }))
+            }
+        }
+    }
+}
+
+fn times(claps: usize) -> String {
+    if claps > 1 {
+        format!(" {claps} times")
+    } else {
+        String::new()
+    }
+}
+
+fn print_levels(phase: &str, levels: &Levels, baseline: f32) {
+    let rejected = match levels.rejected {
+        0 => String::new(),
+        n => format!(" ({n} rejected as outliers)"),
+    };
+    println!(
+        "{phase} calibration: {} plaps{rejected}, {:.1} dB ± {:.1} dB, {:.1} dB above the noise floor",
+        levels.counted,
+        levels.mean,
+        levels.spread,
+        levels.mean - baseline
+    );
+}
+
+/// What to do with a calibration saved for the device in use.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SavedAction {
+    /// Skip calibration and use the saved one
+    Reuse,
+    /// Calibrate again and replace the saved calibration
+    Refresh,
+    /// Calibrate again and forget the saved calibration
+    Discard,
+}
+
+/// The settings that decide what a calibration's levels mean.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub detector: DetectorKind,
+    pub loudness: Loudness,
+    pub filters: Vec<FilterSpec>,
+}
+
+impl Measurement {
+    pub fn new(config: &DetectorConfig) -> Self {
+        Self {
+            detector: config.detector,
+            loudness: config.loudness,
+            filters: config.filters.clone(),
+        }
+    }
+}
+
+/// A calibration kept between sessions, along with what it was measured under.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedCalibration {
+    pub calibration: Calibration,
+    /// Missing from calibrations saved before it was recorded.
+    #[serde(default)]
+    pub measurement: Option<Measurement>,
+    pub tolerance: f32,
+    pub sample_rate: u32,
+    /// Seconds since the Unix epoch.
+    pub saved_at: u64,
+}
+
+impl SavedCalibration {
+    pub fn new(
+        calibration: Calibration,
+        measurement: Measurement,
+        tolerance: f32,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            calibration,
+            measurement: Some(measurement),
+            tolerance,
+            sample_rate,
+            saved_at: history::unix_time(),
+        }
+    }
+
+    /// How the current settings measure levels differently from when the calibration was saved,
+    /// if they do.
+    pub fn mismatch(&self, current: &Measurement) -> Option<&'static str> {
+        let Some(saved) = &self.measurement else {
+            return Some("settings that weren't recorded");
+        };
+        if saved.detector != current.detector {
+            Some("a different detector")
+        } else if saved.loudness != current.loudness {
+            Some("a different loudness measure")
+        } else if saved.filters != current.filters {
+            Some("different filters")
+        } else {
+            None
+        }
+    }
+
+    /// Warns if the saved calibration looks out of date for the current noise floor or stream, or
+    /// was saved under a different tolerance than the one now applied to it.
+    pub fn warn_if_stale(&self, baseline: f32, sample_rate: u32, tolerance: f32) {
+        let difference = baseline - self.calibration.baseline;
+        if difference.abs() > STALE_BASELINE_DIFFERENCE {
+            let direction = if difference > 0.0 {
+                "louder"
+            } else {
+                "quieter"
+            };
+            println!(
+                "Warning: the noise floor is {:.1} dB {} than when this calibration was saved, \
+                 consider calibrating again.",
+                difference.abs(),
+                direction
+            );
+        }
+        if sample_rate != self.sample_rate {
+            println!(
+                "Warning: this calibration was saved at {} Hz but the input is running at {} Hz.",
+                self.sample_rate, sample_rate
+            );
+        }
+        if tolerance != self.tolerance {
+            println!(
+                "Warning: this calibration was saved with a tolerance of {} but {} is set now, the \
+                 hard threshold will follow the current one.",
+                self.tolerance, tolerance
+            );
+        }
+    }
+
+    /// Human-readable time since the calibration was saved.
+    pub fn age(&self) -> String {
+        let secs = history::unix_time().saturating_sub(self.saved_at);
+        match secs {
+            0..3600 => format!("{} minutes", secs / 60),
+            3600..86400 => format!("{} hours", secs / 3600),
+            _ => format!("{} days", secs / 86400),
+        }
+    }
+}
+
+fn load_all() -> anyhow::Result<BTreeMap<String, SavedCalibration>> {
+    let path = history::data_dir()?.join(CALIBRATIONS_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn save_all(saved: &BTreeMap<String, SavedCalibration>) -> anyhow::Result<()> {
+    let path = history::data_dir()?.join(CALIBRATIONS_FILE);
+    fs::write(path, serde_json::to_string_pretty(saved)?)?;
+    Ok(())
+}
+
+/// Looks up the calibration saved for the device called `device_name`.
+pub fn load(device_name: &str) -> anyhow::Result<Option<SavedCalibration>> {
+    Ok(load_all()?.remove(device_name))
+}
+
+/// Saves `saved` for the device called `device_name`, replacing any earlier one.
+pub fn save(device_name: &str, saved: SavedCalibration) -> anyhow::Result<()> {
+    let mut all = load_all()?;
+    all.insert(device_name.to_owned(), saved);
+    save_all(&all)
+}
+
+/// Forgets the calibration saved for the device called `device_name`.
+pub fn forget(device_name: &str) -> anyhow::Result<()> {
+    let mut all = load_all()?;
+    if all.remove(device_name).is_some() {
+        save_all(&all)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measure_throws_out_outliers() {
+        let levels = Levels::measure(vec![-10.0, -10.0, -10.0, -10.0, -40.0]);
+        assert_eq!(levels.mean, -10.0);
+        assert_eq!(levels.spread, 0.0);
+        assert_eq!((levels.counted, levels.rejected), (4, 1));
+    }
+
+    #[test]
+    fn measure_keeps_levels_within_the_minimum_deviation() {
+        // The median absolute deviation is 0.1 dB, but outliers are judged against at least
+        // MIN_DEVIATION
+        let levels = Levels::measure(vec![-10.0, -10.1, -10.2, -12.0]);
+        assert_eq!((levels.counted, levels.rejected), (4, 0));
+        assert!((levels.mean - -10.575).abs() < 1e-4);
+    }
+
+    #[test]
+    fn measure_reports_spread() {
+        let levels = Levels::measure(vec![-9.0, -10.0, -11.0]);
+        assert!((levels.mean - -10.0).abs() < 1e-5);
+        assert!((levels.spread - (2.0f32 / 3.0).sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn score_leaves_room_above_the_mean_hard_level() {
+        let calibration = Calibration {
+            baseline: -50.0,
+            hard: Levels {
+                mean: -14.0,
+                spread: 1.0,
+                counted: 5,
+                rejected: 0,
+            },
+            soft: None,
+        };
+        assert_eq!(calibration.score(-50.0, -50.0), 0.0);
+        assert!(calibration.score(-14.0, -50.0) < 100.0);
+        assert_eq!(calibration.score(-14.0 + MIN_SCORE_HEADROOM, -50.0), 100.0);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{
+    calibration::{CalibrationConfig, SavedAction},
+    config::Settings,
+    detector::{ChannelMix, DetectorConfig},
+};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Longest session that can be asked for, in minutes.
+pub const MAX_MINUTES: f32 = 24.0 * 60.0;
+/// Longest analysis frame or hop, in milliseconds.
+const MAX_MILLIS: f32 = 5000.0;
+
+/// Counts plaps picked up by a microphone and grades them against a calibrated level.
+///
+/// Any value not given on the command line is asked for interactively, unless
+/// `--yes` is passed.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Input device, as an index, an exact name or a unique part of a name
+    #[arg(short, long, value_name = "DEVICE")]
+    pub device: Option<String>,
+
+    /// Session length in minutes
+    #[arg(short, long, value_name = "MINS", value_parser = parse_minutes)]
+    pub time_limit: Option<f32>,
+
+    /// Show plap counts after each plap
+    #[arg(short, long, overrides_with = "hide_counts")]
+    pub show_counts: bool,
+
+    /// Don't show plap counts after each plap
+    #[arg(long, overrides_with = "show_counts")]
+    pub hide_counts: bool,
+
+    /// Never prompt; use the default input device and hide counts unless told otherwise
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// List input devices and exit
+    #[arg(short, long)]
+    pub list_devices: bool,
+
+    /// Length of each analysis frame in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 20.0, value_parser = parse_millis, global = true)]
+    pub frame_ms: f32,
+
+    /// Distance between consecutive analysis frames in milliseconds [default: frame length]
+    #[arg(long, value_name = "MS", value_parser = parse_millis, global = true)]
+    pub hop_ms: Option<f32>,
+
+    /// Input channels to analyse, counting from 1 [default: all]
+    #[arg(
+        short,
+        long,
+        value_name = "CHANNEL",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u16).range(1..),
+        global = true
+    )]
+    pub channels: Vec<u16>,
+
+    /// How to combine the analysed channels
+    #[arg(long, value_enum, default_value_t = ChannelMix::Average, global = true)]
+    pub mix: ChannelMix,
+
+    /// Also calibrate soft plaps, and put the hard/soft boundary between the two levels
+    #[arg(long, global = true)]
+    pub soft_calibration: bool,
+
+    /// Plaps to measure in each calibration phase
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u16).range(1..),
+        global = true
+    )]
+    pub calibration_claps: u16,
+
+    /// What to do with the calibration saved for the input device [default: ask, or reuse with
+    /// --yes]
+    #[arg(long, value_enum, value_name = "ACTION")]
+    pub saved_calibration: Option<SavedAction>,
+
+    /// Config file to layer on top of the user config file
+    #[arg(long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Named profile from the config files to use
+    #[arg(long, value_name = "NAME", global = true)]
+    pub profile: Option<String>,
+
+    /// Print the effective configuration and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the detector over a WAV recording instead of a live input device
+    Analyze(AnalyzeArgs),
+}
+
+#[derive(Args)]
+pub struct AnalyzeArgs {
+    /// WAV file to analyse
+    pub file: PathBuf,
+
+    /// Stop counting this many minutes after calibration, as a live session would [default: length
+    /// of the recording]
+    #[arg(short, long, value_name = "MINS", value_parser = parse_minutes)]
+    pub time_limit: Option<f32>,
+}
+
+impl Cli {
+    /// Whether counts were explicitly turned on or off.
+    pub fn show_counts(&self) -> Option<bool> {
+        match (self.show_counts, self.hide_counts) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn calibration_config(&self, settings: &Settings) -> CalibrationConfig {
+        CalibrationConfig {
+            soft_phase: self.soft_calibration,
+            claps: self.calibration_claps as usize,
+            tolerance: settings.calibration_tolerance,
+            min_hard_margin: settings.min_hard_margin,
+            complete_delay: settings.calibration_complete_delay(),
+        }
+    }
+
+    pub fn detector_config(&self, settings: &Settings) -> anyhow::Result<DetectorConfig> {
+        let hop_ms = self.hop_ms.unwrap_or(self.frame_ms);
+        if hop_ms > self.frame_ms {
+            return Err(anyhow::anyhow!(
+                "--hop-ms ({hop_ms}) can't be longer than --frame-ms ({})",
+                self.frame_ms
+            ));
+        }
+
+        let channels = (!self.channels.is_empty())
+            .then(|| self.channels.iter().map(|&c| c as usize - 1).collect());
+
+        Ok(DetectorConfig {
+            frame_ms: self.frame_ms,
+            hop_ms,
+            channels,
+            mix: self.mix,
+            filters: settings.filters.clone(),
+            detector: settings.detector,
+            loudness: settings.loudness,
+            baseline_window: settings.baseline_window(),
+            baseline_percentile: settings.baseline_percentile,
+            peak_threshold: settings.peak_threshold,
+            reset_threshold: settings.reset_threshold,
+            miss_threshold: settings.miss_threshold,
+            refractory: settings.refractory(),
+            echo_window: settings.echo_window(),
+            max_onset: settings.max_onset(),
+            max_noise: settings.max_noise(),
+        })
+    }
+}
+
+fn parse_minutes(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(m) if m > 0.0 && m <= MAX_MINUTES => Ok(m),
+        Ok(_) => Err(format!(
+            "must be more than 0 and at most {MAX_MINUTES} minutes"
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_millis(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(ms) if (1.0..=MAX_MILLIS).contains(&ms) => Ok(ms),
+        Ok(_) => Err(format!("must be between 1 and {MAX_MILLIS} milliseconds")),
+        Err(e) => Err(e.to_string()),
+    }
+}
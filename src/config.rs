@@ -0,0 +1,469 @@
+use crate::{
+    filter::FilterSpec,
+    strategies::{DetectorKind, Loudness},
+    tiers::{self, Tier},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Tuning values that used to be compiled in, after merging every config file and profile.
+#[derive(Serialize)]
+pub struct Settings {
+    /// Onset detection function.
+    pub detector: DetectorKind,
+    /// How the level of each frame is measured, for onsets, the noise floor and calibration alike.
+    pub loudness: Loudness,
+    /// How far above the noise floor a frame has to be to start an onset, in dB.
+    pub peak_threshold: f32,
+    /// How far below the peak threshold the level has to fall before the next onset, in dB.
+    pub reset_threshold: f32,
+    /// How far above the noise floor an impulse that doesn't reach the peak threshold has to be
+    /// to count as a miss, in dB, or 0 to ignore misses.
+    pub miss_threshold: f32,
+    /// Shown for each miss, or nothing if empty.
+    pub miss_message: String,
+    /// Shortest time from an onset until the next one can start.
+    pub refractory_ms: f32,
+    /// How long after an onset a quieter peak that fits its decay is taken as an echo, or 0 to
+    /// count every peak.
+    pub echo_window_ms: f32,
+    /// Longest an onset can stay loud for before it's taken as background noise and not counted.
+    pub max_onset_ms: f32,
+    /// How long background noise holds the noise floor at its level from before the noise, after
+    /// which the noise is taken as the new background.
+    pub max_noise_ms: f32,
+    /// Length of the window the noise floor is estimated over.
+    pub baseline_window_ms: f32,
+    /// Percentile of the recent frame levels taken as the noise floor.
+    pub baseline_percentile: f32,
+    /// How often the session loop checks for new frames, in Hz.
+    pub poll_frequency: f32,
+    /// Fraction of the hard plap level above the noise floor an onset has to reach to count as
+    /// hard, when soft plaps weren't calibrated.
+    pub calibration_tolerance: f32,
+    /// How far above the noise floor hard plaps have to be for calibration to succeed, in dB.
+    pub min_hard_margin: f32,
+    /// How long a new stream is ignored for while the detector settles.
+    pub active_delay_ms: f32,
+    /// How long calibration waits after the last plap before finishing a phase.
+    pub calibration_complete_delay_ms: f32,
+    /// Filters applied, in order, before detection.
+    pub filters: Vec<FilterSpec>,
+    /// Intensity tiers plaps are sorted into, from quietest to loudest.
+    pub tiers: Vec<Tier>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            detector: DetectorKind::Level,
+            loudness: Loudness::Rms,
+            peak_threshold: 15.0,
+            reset_threshold: 4.0,
+            miss_threshold: 8.0,
+            miss_message: "Too weak, didn't count!".to_owned(),
+            refractory_ms: 160.0,
+            echo_window_ms: 300.0,
+            max_onset_ms: 500.0,
+            max_noise_ms: 30000.0,
+            baseline_window_ms: 1000.0,
+            baseline_percentile: 25.0,
+            poll_frequency: 50.0,
+            calibration_tolerance: 0.9,
+            min_hard_margin: 20.0,
+            active_delay_ms: 1000.0,
+            calibration_complete_delay_ms: 2000.0,
+            filters: Vec::new(),
+            tiers: tiers::default_tiers(),
+        }
+    }
+}
+
+impl Settings {
+    fn validate(&self) -> anyhow::Result<()> {
+        check("peak_threshold", self.peak_threshold, 1.0..=60.0)?;
+        check("reset_threshold", self.reset_threshold, 0.0..=60.0)?;
+        if self.miss_threshold != 0.0 {
+            check(
+                "miss_threshold",
+                self.miss_threshold,
+                1.0..=self.peak_threshold - 1.0,
+            )?;
+        }
+        check("refractory_ms", self.refractory_ms, 1.0..=5000.0)?;
+        check("echo_window_ms", self.echo_window_ms, 0.0..=5000.0)?;
+        check("max_onset_ms", self.max_onset_ms, 50.0..=10000.0)?;
+        check("max_noise_ms", self.max_noise_ms, 0.0..=600000.0)?;
+        check(
+            "baseline_window_ms",
+            self.baseline_window_ms,
+            100.0..=60000.0,
+        )?;
+        check("baseline_percentile", self.baseline_percentile, 0.0..=100.0)?;
+        check("poll_frequency", self.poll_frequency, 1.0..=1000.0)?;
+        check(
+            "calibration_tolerance",
+            self.calibration_tolerance,
+            0.0..=1.0,
+        )?;
+        check("min_hard_margin", self.min_hard_margin, 1.0..=60.0)?;
+        check("active_delay_ms", self.active_delay_ms, 0.0..=10000.0)?;
+        check(
+            "calibration_complete_delay_ms",
+            self.calibration_complete_delay_ms,
+            100.0..=30000.0,
+        )?;
+        for filter in &self.filters {
+            check("filter frequency", filter.frequency(), 10.0..=20000.0)?;
+            check("filter q", filter.q(), 0.1..=20.0)?;
+        }
+        tiers::validate(&self.tiers)?;
+        Ok(())
+    }
+
+    pub fn refractory(&self) -> Duration {
+        millis(self.refractory_ms)
+    }
+
+    pub fn echo_window(&self) -> Duration {
+        millis(self.echo_window_ms)
+    }
+
+    pub fn max_onset(&self) -> Duration {
+        millis(self.max_onset_ms)
+    }
+
+    pub fn max_noise(&self) -> Duration {
+        millis(self.max_noise_ms)
+    }
+
+    pub fn baseline_window(&self) -> Duration {
+        millis(self.baseline_window_ms)
+    }
+
+    pub fn active_delay(&self) -> Duration {
+        millis(self.active_delay_ms)
+    }
+
+    pub fn calibration_complete_delay(&self) -> Duration {
+        millis(self.calibration_complete_delay_ms)
+    }
+}
+
+fn millis(ms: f32) -> Duration {
+    Duration::from_secs_f32(ms / 1000.0)
+}
+
+fn check(name: &str, value: f32, range: RangeInclusive<f32>) -> anyhow::Result<()> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{name} must be between {} and {}, got {value}",
+            range.start(),
+            range.end()
+        ))
+    }
+}
+
+/// Settings given in a config file or profile; anything left out keeps its earlier value.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overrides {
+    detector: Option<DetectorKind>,
+    loudness: Option<Loudness>,
+    peak_threshold: Option<f32>,
+    reset_threshold: Option<f32>,
+    miss_threshold: Option<f32>,
+    miss_message: Option<String>,
+    refractory_ms: Option<f32>,
+    echo_window_ms: Option<f32>,
+    max_onset_ms: Option<f32>,
+    max_noise_ms: Option<f32>,
+    baseline_window_ms: Option<f32>,
+    baseline_percentile: Option<f32>,
+    poll_frequency: Option<f32>,
+    calibration_tolerance: Option<f32>,
+    min_hard_margin: Option<f32>,
+    active_delay_ms: Option<f32>,
+    calibration_complete_delay_ms: Option<f32>,
+    filters: Option<Vec<FilterSpec>>,
+    tiers: Option<Vec<Tier>>,
+}
+
+impl Overrides {
+    /// Layers `other` on top, so its values win.
+    fn merge(&mut self, other: &Overrides) {
+        if other.detector.is_some() {
+            self.detector = other.detector;
+        }
+        if other.loudness.is_some() {
+            self.loudness = other.loudness;
+        }
+        if other.filters.is_some() {
+            self.filters.clone_from(&other.filters);
+        }
+        if other.tiers.is_some() {
+            self.tiers.clone_from(&other.tiers);
+        }
+        if other.miss_message.is_some() {
+            self.miss_message.clone_from(&other.miss_message);
+        }
+
+        let fields = [
+            (&mut self.peak_threshold, other.peak_threshold),
+            (&mut self.reset_threshold, other.reset_threshold),
+            (&mut self.miss_threshold, other.miss_threshold),
+            (&mut self.refractory_ms, other.refractory_ms),
+            (&mut self.echo_window_ms, other.echo_window_ms),
+            (&mut self.max_onset_ms, other.max_onset_ms),
+            (&mut self.max_noise_ms, other.max_noise_ms),
+            (&mut self.baseline_window_ms, other.baseline_window_ms),
+            (&mut self.baseline_percentile, other.baseline_percentile),
+            (&mut self.poll_frequency, other.poll_frequency),
+            (&mut self.calibration_tolerance, other.calibration_tolerance),
+            (&mut self.min_hard_margin, other.min_hard_margin),
+            (&mut self.active_delay_ms, other.active_delay_ms),
+            (
+                &mut self.calibration_complete_delay_ms,
+                other.calibration_complete_delay_ms,
+            ),
+        ];
+        for (field, value) in fields {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+    }
+
+    /// Fills in whatever wasn't given from the built-in defaults.
+    fn resolve(&self) -> Settings {
+        let defaults = Settings::default();
+        Settings {
+            detector: self.detector.unwrap_or(defaults.detector),
+            loudness: self.loudness.unwrap_or(defaults.loudness),
+            peak_threshold: self.peak_threshold.unwrap_or(defaults.peak_threshold),
+            reset_threshold: self.reset_threshold.unwrap_or(defaults.reset_threshold),
+            miss_threshold: self.miss_threshold.unwrap_or(defaults.miss_threshold),
+            miss_message: self.miss_message.clone().unwrap_or(defaults.miss_message),
+            refractory_ms: self.refractory_ms.unwrap_or(defaults.refractory_ms),
+            echo_window_ms: self.echo_window_ms.unwrap_or(defaults.echo_window_ms),
+            max_onset_ms: self.max_onset_ms.unwrap_or(defaults.max_onset_ms),
+            max_noise_ms: self.max_noise_ms.unwrap_or(defaults.max_noise_ms),
+            baseline_window_ms: self
+                .baseline_window_ms
+                .unwrap_or(defaults.baseline_window_ms),
+            baseline_percentile: self
+                .baseline_percentile
+                .unwrap_or(defaults.baseline_percentile),
+            poll_frequency: self.poll_frequency.unwrap_or(defaults.poll_frequency),
+            calibration_tolerance: self
+                .calibration_tolerance
+                .unwrap_or(defaults.calibration_tolerance),
+            min_hard_margin: self.min_hard_margin.unwrap_or(defaults.min_hard_margin),
+            active_delay_ms: self.active_delay_ms.unwrap_or(defaults.active_delay_ms),
+            calibration_complete_delay_ms: self
+                .calibration_complete_delay_ms
+                .unwrap_or(defaults.calibration_complete_delay_ms),
+            filters: self.filters.clone().unwrap_or(defaults.filters),
+            tiers: self.tiers.clone().unwrap_or(defaults.tiers),
+        }
+    }
+}
+
+/// Layout of a config file.
+///
+/// ```toml
+/// # Profile used when --profile isn't given
+/// profile = "noisy"
+///
+/// # Settings for every profile
+/// [settings]
+/// peak_threshold = 15.0
+///
+/// # Filters run in order, before detection
+/// [[settings.filters]]
+/// type = "high-pass"
+/// cutoff_hz = 300.0
+///
+/// # Intensity tiers, from quietest to loudest, by how far above the calibrated hard threshold
+/// # plaps peak
+/// [[settings.tiers]]
+/// name = "soft"
+///
+/// [[settings.tiers]]
+/// name = "hard"
+/// min_db = 0.0
+/// message = "Good girl~!"
+///
+/// # Named sets of settings that override the ones above
+/// [profiles.noisy]
+/// peak_threshold = 20.0
+/// reset_threshold = 6.0
+/// ```
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    profile: Option<String>,
+    settings: Overrides,
+    profiles: BTreeMap<String, Overrides>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Couldn't read {}: {e}", path.display()))?;
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("Invalid config {}: {e}", path.display()))
+    }
+
+    /// Layers `other` on top, so its values win.
+    fn merge(&mut self, other: ConfigFile) {
+        if other.profile.is_some() {
+            self.profile = other.profile;
+        }
+        self.settings.merge(&other.settings);
+        for (name, profile) in other.profiles {
+            self.profiles.entry(name).or_default().merge(&profile);
+        }
+    }
+}
+
+/// The merged configuration and where it came from.
+pub struct Config {
+    pub settings: Settings,
+    pub profile: Option<String>,
+    pub sources: Vec<PathBuf>,
+}
+
+impl Config {
+    /// Merges the built-in defaults, the user config file, the file at `path` and then the
+    /// selected profile, in that order.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> anyhow::Result<Self> {
+        let mut file = ConfigFile::default();
+        let mut sources = Vec::new();
+
+        if let Some(user) = dirs::config_dir().map(|dir| dir.join("clapcounter").join(CONFIG_FILE))
+        {
+            if user.exists() {
+                file.merge(ConfigFile::read(&user)?);
+                sources.push(user);
+            }
+        }
+        if let Some(path) = path {
+            file.merge(ConfigFile::read(path)?);
+            sources.push(path.to_owned());
+        }
+
+        let mut overrides = file.settings;
+        let profile = profile.map(str::to_owned).or(file.profile);
+        if let Some(name) = &profile {
+            let Some(profile_overrides) = file.profiles.get(name) else {
+                let known: Vec<&str> = file.profiles.keys().map(String::as_str).collect();
+                return Err(anyhow::anyhow!(
+                    "No profile called \"{name}\", the config has: {}",
+                    if known.is_empty() {
+                        "none".to_owned()
+                    } else {
+                        known.join(", ")
+                    }
+                ));
+            };
+            overrides.merge(profile_overrides);
+        }
+
+        let settings = overrides.resolve();
+        settings.validate()?;
+
+        Ok(Self {
+            settings,
+            profile,
+            sources,
+        })
+    }
+
+    /// Prints the effective configuration in config file syntax.
+    pub fn print(&self) -> anyhow::Result<()> {
+        if self.sources.is_empty() {
+            println!("# No config files, using the built-in defaults");
+        }
+        for source in &self.sources {
+            println!("# From {}", source.display());
+        }
+        if let Some(profile) = &self.profile {
+            println!("# With profile \"{profile}\"");
+        }
+        #[derive(Serialize)]
+        struct Printed<'a> {
+            settings: &'a Settings,
+        }
+        print!(
+            "{}",
+            toml::to_string(&Printed {
+                settings: &self.settings
+            })?
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(text: &str) -> Overrides {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn merge_keeps_values_the_other_side_leaves_out() {
+        let mut base = overrides(
+            r#"
+            detector = "spectral-flux"
+            peak_threshold = 20.0
+            miss_message = "Nope"
+            "#,
+        );
+        base.merge(&overrides("peak_threshold = 25.0\nreset_threshold = 6.0"));
+
+        let settings = base.resolve();
+        assert!(settings.detector == DetectorKind::SpectralFlux);
+        assert_eq!(settings.peak_threshold, 25.0);
+        assert_eq!(settings.reset_threshold, 6.0);
+        assert_eq!(settings.miss_message, "Nope");
+        assert_eq!(settings.refractory_ms, Settings::default().refractory_ms);
+    }
+
+    #[test]
+    fn merge_replaces_lists_whole() {
+        let mut base = overrides(
+            r#"
+            [[filters]]
+            type = "high-pass"
+            cutoff_hz = 300.0
+
+            [[filters]]
+            type = "low-pass"
+            cutoff_hz = 8000.0
+            "#,
+        );
+        base.merge(&overrides(
+            r#"
+            [[filters]]
+            type = "high-pass"
+            cutoff_hz = 100.0
+            "#,
+        ));
+
+        let filters = base.resolve().filters;
+        assert_eq!(filters.len(), 1);
+        assert_eq!(filters[0].frequency(), 100.0);
+    }
+}
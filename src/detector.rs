@@ -0,0 +1,541 @@
+use crate::{
+    filter::{FilterChain, FilterSpec},
+    strategies::{DetectorKind, Loudness, Meter},
+};
+use clap::ValueEnum;
+use cpal::{FromSample, Sample};
+use std::{collections::VecDeque, time::Duration};
+
+/// How far above the previous onset's decay a peak inside the echo window can be and still be
+/// taken as its echo, in dB.
+const ECHO_MARGIN: f32 = 3.0;
+/// Fastest decay assumed when predicting where the previous onset's envelope has got to, in dB
+/// per second. The level of a frame falls faster than a room's reverb right after the direct
+/// sound, so the measured decay alone would let late reflections through.
+const MAX_ECHO_DECAY: f32 = 80.0;
+
+/// Analysis frame layout, independent of the device and its callback buffer size, and the onset
+/// detection settings.
+pub struct DetectorConfig {
+    /// Length of each analysis frame in milliseconds.
+    pub frame_ms: f32,
+    /// Distance between the starts of consecutive frames in milliseconds.
+    pub hop_ms: f32,
+    /// Zero-based input channels to analyse, or every channel if `None`.
+    pub channels: Option<Vec<usize>>,
+    pub mix: ChannelMix,
+    /// Filters applied to the mixed-down signal before detection.
+    pub filters: Vec<FilterSpec>,
+    pub detector: DetectorKind,
+    /// How onsets and the noise floor are measured for calibration and classification, and how
+    /// the level-based detectors measure each frame.
+    pub loudness: Loudness,
+    /// Length of the window the noise floor is estimated over.
+    pub baseline_window: Duration,
+    /// Percentile of the frame levels in the window taken as the noise floor.
+    pub baseline_percentile: f32,
+    /// How far above the noise floor a frame has to be to start an onset, in dB.
+    pub peak_threshold: f32,
+    /// How far below the peak threshold the level has to fall before the next onset, in dB.
+    pub reset_threshold: f32,
+    /// How far above the noise floor a frame has to be to start a miss, in dB, or 0 to not look
+    /// for misses.
+    pub miss_threshold: f32,
+    /// Shortest time from an onset until the next one can start.
+    pub refractory: Duration,
+    /// How long after an onset a quieter peak that fits its decay is taken as an echo.
+    pub echo_window: Duration,
+    /// Longest an onset can stay loud for before it's taken as background noise.
+    pub max_onset: Duration,
+    /// How long background noise holds the noise floor at its level from before the noise.
+    /// Noise that lasts longer is taken as the new background and the noise floor follows it.
+    pub max_noise: Duration,
+}
+
+/// How the selected input channels are combined into the signal that gets analysed.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ChannelMix {
+    /// Average the channels' samples
+    Average,
+    /// Add the channels' samples together
+    Sum,
+    /// Take whichever channel's sample is loudest at each instant
+    Max,
+}
+
+impl ChannelMix {
+    fn apply(self, samples: impl Iterator<Item = f32>) -> f32 {
+        match self {
+            ChannelMix::Average => {
+                let (sum, count) = samples.fold((0.0, 0), |(sum, count), s| (sum + s, count + 1));
+                sum / count as f32
+            }
+            ChannelMix::Sum => samples.sum(),
+            ChannelMix::Max => {
+                samples.fold(0.0, |max, s| if s.abs() > max.abs() { s } else { max })
+            }
+        }
+    }
+}
+
+/// Level measurements for one analysis frame.
+#[derive(Clone, Copy)]
+pub struct Frame {
+    /// Sample position just past the end of the frame.
+    pub end: u64,
+    /// Onset detection level, in dB.
+    pub db: f32,
+    /// Level by the configured loudness measure, in dB.
+    pub loudness: f32,
+    /// Noise floor of `loudness`.
+    pub loudness_floor: f32,
+    /// An onset that this frame confirmed as an impulse.
+    pub onset: Option<Onset>,
+    /// An impulse that this frame saw fall off again without reaching the peak threshold.
+    pub miss: Option<Onset>,
+    pub noise: Option<Noise>,
+}
+
+/// An impulsive onset, reported once its level has fallen again.
+#[derive(Clone, Copy)]
+pub struct Onset {
+    /// Sample position the onset started at.
+    pub position: u64,
+    /// Highest onset detection level reached during the onset, in dB.
+    pub db: f32,
+    /// Loudest frame reached during the onset by the configured loudness measure, in dB. This is
+    /// what calibration and classification go by, whichever detector found the onset.
+    pub loudness: f32,
+    /// Sample position of the loudest sample in the loudest frame.
+    pub peak_at: u64,
+    /// Sample position at the end of the frame the level fell off in.
+    pub end: u64,
+}
+
+/// Changes in whether the detector is hearing loud non-impulsive noise.
+#[derive(Clone, Copy)]
+pub enum Noise {
+    /// An onset stayed loud for longer than `max_onset` and was thrown out.
+    TooLoud,
+    /// The noise has died down to the noise floor from before it and the detector has re-armed.
+    Settled,
+    /// The noise went on for longer than `max_noise`, and the detector has re-armed on top of it.
+    Adapted,
+}
+
+/// Onset detection function: reduces each analysis frame to the level that onsets are detected
+/// on.
+///
+/// Levels are in dB so that the noise floor, the thresholds and calibration work the same way
+/// whichever detector is in use.
+pub trait Detector: Send {
+    /// Level of `frame`, where `new` is the part of it that wasn't in the previous frame: all of
+    /// it for the first frame, then the last hop of samples. `None` until the detector has seen
+    /// enough of the stream to measure anything.
+    fn level(&mut self, frame: &[f32], new: &[f32]) -> Option<f32>;
+
+    /// Index of the sample in `frame` that the onset starts at, given that the frame's level
+    /// went over `threshold`. Defaults to the first sample reaching half the frame's peak.
+    fn onset_index(&self, frame: &[f32], _threshold: f32) -> usize {
+        let peak = frame
+            .iter()
+            .fold(0.0f32, |max, sample| max.max(sample.abs()));
+        frame
+            .iter()
+            .position(|sample| sample.abs() >= peak / 2.0)
+            .unwrap_or(0)
+    }
+}
+
+/// Splits an interleaved sample stream into fixed-size, possibly overlapping frames and detects
+/// onsets in them.
+///
+/// Frames are laid out by `DetectorConfig` regardless of how the stream is chunked into
+/// callbacks, and all positions are counted in samples from the start of the stream.
+pub struct Analyser {
+    channels: usize,
+    selected_channels: Vec<usize>,
+    mix: ChannelMix,
+    filter: FilterChain,
+    detector: Box<dyn Detector>,
+    meter: Meter,
+    frame_length: usize,
+    hop_length: usize,
+    /// Mixed-down samples of the frame being filled.
+    frame: Vec<f32>,
+    /// Sample position of the start of `frame`.
+    position: u64,
+    /// Noise floor of the detection level.
+    baseline: NoiseFloor,
+    /// Noise floor of the loudness.
+    loudness_floor: NoiseFloor,
+    peak_threshold: f32,
+    reset_threshold: f32,
+    miss_threshold: f32,
+    /// Samples after an onset before the detector can re-arm.
+    refractory: u64,
+    /// Samples after an onset that peaks fitting its decay are ignored for.
+    echo_window: u64,
+    /// Fastest decay of `MAX_ECHO_DECAY`, in dB per sample.
+    max_decay: f32,
+    /// Samples an onset can stay loud for before it's taken as noise.
+    max_onset: u64,
+    /// Whether the next frame above the peak threshold starts an onset.
+    armed: bool,
+    last_onset: Option<Envelope>,
+    /// The onset being tracked, until it either falls off again or outlasts `max_onset`.
+    pending: Option<Onset>,
+    /// Sample position loud noise started at, while the detector waits for it to end before it
+    /// re-arms.
+    noise_since: Option<u64>,
+    /// Whether the noise floors are held at their level from before the noise. They only are if
+    /// they'd filled their window by then, so that a false alarm while the stream is still
+    /// starting up doesn't hold a floor that hadn't settled.
+    hold_floors: bool,
+    max_noise: u64,
+    /// Whether the level has been below the miss threshold since the last miss.
+    miss_armed: bool,
+    /// The miss being tracked, until it falls off again.
+    near_miss: Option<Onset>,
+}
+
+/// A percentile of the levels of recent frames.
+struct NoiseFloor {
+    /// Levels of the most recent frames, oldest first.
+    levels: VecDeque<f32>,
+    /// Scratch space for picking the percentile out of `levels`.
+    sorted: Vec<f32>,
+    window: usize,
+    percentile: f32,
+    level: f32,
+}
+
+impl NoiseFloor {
+    fn new(window: usize, percentile: f32) -> Self {
+        Self {
+            levels: VecDeque::with_capacity(window),
+            sorted: Vec::with_capacity(window),
+            window,
+            percentile,
+            level: 0.0,
+        }
+    }
+
+    /// Whether the window has filled up, which it stays once it has.
+    fn is_full(&self) -> bool {
+        self.levels.len() == self.window
+    }
+
+    fn update(&mut self, db: f32) {
+        if self.levels.len() == self.window {
+            self.levels.pop_front();
+        }
+        self.levels.push_back(db);
+
+        self.sorted.clear();
+        self.sorted.extend(&self.levels);
+        let last = self.sorted.len() - 1;
+        let index = (last as f32 * self.percentile / 100.0).round() as usize;
+        let (_, &mut level, _) = self.sorted.select_nth_unstable_by(index, f32::total_cmp);
+        self.level = level;
+    }
+}
+
+/// The level of an onset after it started, for telling its echoes apart from new onsets.
+struct Envelope {
+    /// Sample position at the end of the frame the onset was detected in.
+    at: u64,
+    peak_db: f32,
+    peak_at: u64,
+    /// How fast the level fell from the peak until the detector re-armed, in dB per sample.
+    decay: f32,
+}
+
+impl Envelope {
+    /// Predicted level at sample position `at`, had the decay carried on.
+    fn level_at(&self, at: u64) -> f32 {
+        self.peak_db - self.decay * (at - self.peak_at) as f32
+    }
+}
+
+impl Analyser {
+    pub fn new(config: &DetectorConfig, sample_rate: u32, channels: usize) -> anyhow::Result<Self> {
+        let selected_channels = match &config.channels {
+            Some(selected) => {
+                if let Some(&c) = selected.iter().find(|&&c| c >= channels) {
+                    return Err(anyhow::anyhow!(
+                        "Can't analyse channel {}, the input only has {channels}",
+                        c + 1
+                    ));
+                }
+                selected.clone()
+            }
+            None => (0..channels).collect(),
+        };
+
+        let samples_in = |ms: f32| (ms / 1000.0 * sample_rate as f32).round().max(1.0) as usize;
+        let hops_in = |d: Duration| (d.as_secs_f32() * 1000.0 / config.hop_ms).round().max(1.0);
+        let samples_for = |d: Duration| (d.as_secs_f64() * sample_rate as f64).round() as u64;
+
+        let frame_length = samples_in(config.frame_ms);
+        let hop_length = samples_in(config.hop_ms).min(frame_length);
+        let baseline_window = hops_in(config.baseline_window) as usize;
+        Ok(Self {
+            channels,
+            selected_channels,
+            mix: config.mix,
+            filter: FilterChain::new(&config.filters, sample_rate)?,
+            detector: config
+                .detector
+                .build(config.loudness, frame_length, sample_rate),
+            meter: Meter::new(config.loudness, frame_length, sample_rate),
+            frame_length,
+            hop_length,
+            frame: Vec::with_capacity(frame_length),
+            position: 0,
+            baseline: NoiseFloor::new(baseline_window, config.baseline_percentile),
+            loudness_floor: NoiseFloor::new(baseline_window, config.baseline_percentile),
+            peak_threshold: config.peak_threshold,
+            reset_threshold: config.reset_threshold,
+            miss_threshold: config.miss_threshold,
+            refractory: samples_for(config.refractory),
+            echo_window: samples_for(config.echo_window),
+            max_decay: MAX_ECHO_DECAY / sample_rate as f32,
+            max_onset: samples_for(config.max_onset),
+            armed: true,
+            last_onset: None,
+            pending: None,
+            noise_since: None,
+            hold_floors: false,
+            max_noise: samples_for(config.max_noise),
+            miss_armed: false,
+            near_miss: None,
+        })
+    }
+
+    /// Feeds interleaved samples in, calling `emit` for every frame they complete that the
+    /// detector could measure.
+    pub fn process<T>(&mut self, data: &[T], mut emit: impl FnMut(Frame))
+    where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        for samples in data.chunks_exact(self.channels) {
+            let sample = self.mix.apply(
+                self.selected_channels
+                    .iter()
+                    .map(|&c| samples[c].to_sample()),
+            );
+            self.frame.push(self.filter.process(sample));
+            if self.frame.len() == self.frame_length {
+                if let Some(frame) = self.analyse_frame() {
+                    emit(frame);
+                }
+                self.frame.drain(..self.hop_length);
+                self.position += self.hop_length as u64;
+            }
+        }
+    }
+
+    fn analyse_frame(&mut self) -> Option<Frame> {
+        let new = if self.position == 0 {
+            &self.frame[..]
+        } else {
+            &self.frame[self.frame_length - self.hop_length..]
+        };
+        // The meter has to see every sample, even before the detector can measure anything
+        let loudness = self.meter.measure(&self.frame, new);
+        // A placeholder level here would drag both noise floors down, and the first real one
+        // would then look like an onset
+        let db = self.detector.level(&self.frame, new)?;
+
+        // Frames inside an onset or miss are left out of the noise floor, so that runs of plaps
+        // don't raise it, and so is loud noise until it's lasted `max_noise`, so that it has to
+        // die down to the level from before it to count as settled
+        let end = self.position + self.frame_length as u64;
+        let in_noise = self.hold_floors
+            && self
+                .noise_since
+                .is_some_and(|since| end - since < self.max_noise);
+        if self.pending.is_none() && self.near_miss.is_none() && !in_noise {
+            self.baseline.update(db);
+            self.loudness_floor.update(loudness);
+        }
+
+        let mut frame = Frame {
+            end,
+            db,
+            loudness,
+            loudness_floor: self.loudness_floor.level,
+            onset: None,
+            miss: None,
+            noise: None,
+        };
+        if self.armed {
+            self.detect_peak(&frame);
+            if self.armed {
+                self.track_miss(&mut frame);
+            } else {
+                // It went on to be an onset after all
+                self.near_miss = None;
+            }
+        } else {
+            self.track_peak(&mut frame);
+        }
+        Some(frame)
+    }
+
+    /// Position of the sample in the current frame that an onset over `threshold` starts at.
+    fn onset_position(&self, threshold: f32) -> u64 {
+        let index = self.detector.onset_index(&self.frame, threshold);
+        self.position + index as u64
+    }
+
+    /// Position of the loudest sample in the current frame.
+    fn peak_position(&self) -> u64 {
+        let (index, _) =
+            self.frame
+                .iter()
+                .enumerate()
+                .fold((0, 0.0f32), |(index, max), (i, sample)| {
+                    if sample.abs() > max {
+                        (i, sample.abs())
+                    } else {
+                        (index, max)
+                    }
+                });
+        self.position + index as u64
+    }
+
+    fn peak_threshold(&self) -> f32 {
+        self.baseline.level + self.peak_threshold
+    }
+
+    fn reset_threshold(&self) -> f32 {
+        self.peak_threshold() - self.reset_threshold
+    }
+
+    /// Starts tracking an onset if one begins in `frame`.
+    fn detect_peak(&mut self, frame: &Frame) {
+        let (db, at) = (frame.db, frame.end);
+        if db <= self.peak_threshold() {
+            return;
+        }
+
+        self.armed = false;
+        if self.is_echo(db, at) {
+            return;
+        }
+
+        self.pending = Some(Onset {
+            position: self.onset_position(self.peak_threshold()),
+            db,
+            loudness: frame.loudness,
+            peak_at: self.peak_position(),
+            end: at,
+        });
+        self.last_onset = Some(Envelope {
+            at,
+            peak_db: db,
+            peak_at: at,
+            decay: 0.0,
+        });
+    }
+
+    /// Follows an impulse that rises above the miss threshold while the detector is armed,
+    /// reporting it as a miss once it falls off again. Like onsets, misses that stay up too long
+    /// aren't impulses and are dropped.
+    fn track_miss(&mut self, frame: &mut Frame) {
+        if self.miss_threshold == 0.0 {
+            return;
+        }
+        let (db, at) = (frame.db, frame.end);
+        let miss_level = self.baseline.level + self.miss_threshold;
+        let peak_at = self.peak_position();
+
+        let Some(miss) = &mut self.near_miss else {
+            if self.miss_armed && db > miss_level && !self.is_echo(db, at) {
+                self.near_miss = Some(Onset {
+                    position: self.onset_position(miss_level),
+                    db,
+                    loudness: frame.loudness,
+                    peak_at,
+                    end: at,
+                });
+            }
+            self.miss_armed = db <= miss_level;
+            return;
+        };
+
+        if db > miss.db {
+            miss.db = db;
+            miss.peak_at = peak_at;
+        }
+        miss.loudness = miss.loudness.max(frame.loudness);
+        if db <= miss_level {
+            miss.end = at;
+            frame.miss = self.near_miss.take();
+            self.miss_armed = true;
+        } else if at - miss.position > self.max_onset {
+            self.near_miss = None;
+        }
+    }
+
+    /// Whether a peak at sample position `at` is consistent with the decay of the last onset.
+    fn is_echo(&self, db: f32, at: u64) -> bool {
+        self.last_onset.as_ref().is_some_and(|onset| {
+            at - onset.at <= self.echo_window && db <= onset.level_at(at) + ECHO_MARGIN
+        })
+    }
+
+    /// Follows the last onset to its peak, confirming it once it falls off or throwing it out as
+    /// noise if it doesn't, and re-arms once the refractory period is over and the level has
+    /// fallen far enough.
+    fn track_peak(&mut self, frame: &mut Frame) {
+        let (db, at) = (frame.db, frame.end);
+        let reset_threshold = self.reset_threshold();
+        let peak_at = self.peak_position();
+        let Some(onset) = &mut self.last_onset else {
+            self.armed = true;
+            return;
+        };
+
+        if db > onset.peak_db {
+            onset.peak_db = db;
+            onset.peak_at = at;
+        }
+
+        if let Some(pending) = &mut self.pending {
+            if db > pending.db {
+                pending.db = db;
+                pending.peak_at = peak_at;
+            }
+            pending.loudness = pending.loudness.max(frame.loudness);
+            if db < reset_threshold {
+                pending.end = at;
+                frame.onset = self.pending.take();
+            } else if at - onset.at > self.max_onset {
+                self.pending = None;
+                self.noise_since = Some(onset.at);
+                self.hold_floors = self.baseline.is_full();
+                frame.noise = Some(Noise::TooLoud);
+            }
+        }
+
+        if at - onset.at >= self.refractory && db < reset_threshold {
+            let decay = (onset.peak_db - db) / (at - onset.peak_at).max(1) as f32;
+            onset.decay = decay.min(self.max_decay);
+            self.armed = true;
+
+            if let Some(since) = self.noise_since.take() {
+                // The noise's envelope says nothing about what comes next
+                self.last_onset = None;
+                frame.noise = Some(if !self.hold_floors || at - since < self.max_noise {
+                    Noise::Settled
+                } else {
+                    Noise::Adapted
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::f64::consts::PI as PI_F64;
+
+/// One filter in the chain applied before detection, as given in the config.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+#[allow(clippy::enum_variant_names)] // Named after the filter types they're written as
+pub enum FilterSpec {
+    /// Cuts rumble below `cutoff_hz`
+    HighPass {
+        cutoff_hz: f32,
+        #[serde(default = "butterworth_q")]
+        q: f32,
+    },
+    /// Cuts hiss above `cutoff_hz`
+    LowPass {
+        cutoff_hz: f32,
+        #[serde(default = "butterworth_q")]
+        q: f32,
+    },
+    /// Keeps a band around `center_hz`, narrower the higher `q` is
+    BandPass {
+        center_hz: f32,
+        #[serde(default = "butterworth_q")]
+        q: f32,
+    },
+}
+
+fn butterworth_q() -> f32 {
+    FRAC_1_SQRT_2
+}
+
+impl FilterSpec {
+    /// Frequency the filter is tuned to, in Hz.
+    pub fn frequency(&self) -> f32 {
+        match *self {
+            FilterSpec::HighPass { cutoff_hz, .. } | FilterSpec::LowPass { cutoff_hz, .. } => {
+                cutoff_hz
+            }
+            FilterSpec::BandPass { center_hz, .. } => center_hz,
+        }
+    }
+
+    pub fn q(&self) -> f32 {
+        match *self {
+            FilterSpec::HighPass { q, .. }
+            | FilterSpec::LowPass { q, .. }
+            | FilterSpec::BandPass { q, .. } => q,
+        }
+    }
+}
+
+/// Second-order IIR section, with coefficients from the Audio EQ Cookbook.
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    /// Transposed direct form II state.
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn new(spec: &FilterSpec, sample_rate: u32) -> Self {
+        let w0 = 2.0 * PI * spec.frequency() / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * spec.q());
+
+        let (b0, b1, b2) = match spec {
+            FilterSpec::HighPass { .. } => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0),
+            FilterSpec::LowPass { .. } => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0),
+            FilterSpec::BandPass { .. } => (alpha, 0.0, -alpha),
+        };
+        let a0 = 1.0 + alpha;
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// Normalises `b` and `a` by `a[0]`.
+    fn from_coefficients(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b0: (b[0] / a[0]) as f32,
+            b1: (b[1] / a[0]) as f32,
+            b2: (b[2] / a[0]) as f32,
+            a1: (a[1] / a[0]) as f32,
+            a2: (a[2] / a[0]) as f32,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// Digital version of the analogue section `b(s) / a(s)` by the bilinear transform, with
+    /// coefficients in ascending powers of `s`.
+    fn bilinear(b: [f64; 3], a: [f64; 3], sample_rate: u32) -> Self {
+        let k = 2.0 * sample_rate as f64;
+        let transform = |c: [f64; 3]| {
+            [
+                c[2] * k * k + c[1] * k + c[0],
+                2.0 * (c[0] - c[2] * k * k),
+                c[2] * k * k - c[1] * k + c[0],
+            ]
+        };
+        Self::from_coefficients(transform(b), transform(a))
+    }
+
+    /// Magnitude response at `frequency` Hz.
+    fn gain_at(&self, frequency: f32, sample_rate: u32) -> f32 {
+        let w = 2.0 * PI * frequency / sample_rate as f32;
+        // Evaluate both polynomials at z^-1 = e^-jw
+        let evaluate = |c0: f32, c1: f32, c2: f32| {
+            let re = c0 + c1 * w.cos() + c2 * (2.0 * w).cos();
+            let im = -c1 * w.sin() - c2 * (2.0 * w).sin();
+            re.hypot(im)
+        };
+        evaluate(self.b0, self.b1, self.b2) / evaluate(1.0, self.a1, self.a2)
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Filters applied one after the other to the mixed-down signal.
+pub struct FilterChain {
+    filters: Vec<Biquad>,
+}
+
+impl FilterChain {
+    pub fn new(specs: &[FilterSpec], sample_rate: u32) -> anyhow::Result<Self> {
+        let nyquist = sample_rate as f32 / 2.0;
+        if let Some(spec) = specs.iter().find(|spec| spec.frequency() >= nyquist) {
+            return Err(anyhow::anyhow!(
+                "Can't filter at {} Hz, the input only goes up to {nyquist} Hz",
+                spec.frequency()
+            ));
+        }
+
+        Ok(Self {
+            filters: specs
+                .iter()
+                .map(|spec| Biquad::new(spec, sample_rate))
+                .collect(),
+        })
+    }
+
+    /// IEC 61672 A-weighting, normalised to 0 dB at 1 kHz.
+    pub fn a_weighting(sample_rate: u32) -> Self {
+        let w = |f: f64| 2.0 * PI_F64 * f;
+        let (w1, w2, w3, w4) = (w(20.598997), w(107.65265), w(737.86223), w(12194.217));
+
+        let mut filters = vec![
+            Biquad::bilinear([0.0, 0.0, 1.0], [w1 * w1, 2.0 * w1, 1.0], sample_rate),
+            Biquad::bilinear([0.0, 0.0, 1.0], [w2 * w3, w2 + w3, 1.0], sample_rate),
+            Biquad::bilinear([1.0, 0.0, 0.0], [w4 * w4, 2.0 * w4, 1.0], sample_rate),
+        ];
+        let gain: f32 = filters
+            .iter()
+            .map(|filter| filter.gain_at(1000.0, sample_rate))
+            .product();
+        let first = &mut filters[0];
+        first.b0 /= gain;
+        first.b1 /= gain;
+        first.b2 /= gain;
+
+        Self { filters }
+    }
+
+    /// ITU-R BS.1770 K-weighting: a high shelf for the head, then a high-pass.
+    pub fn k_weighting(sample_rate: u32) -> Self {
+        let fs = sample_rate as f64;
+
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI_F64 * f0 / fs).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let shelf = Biquad::from_coefficients(
+            [
+                vh + vb * k / q + k * k,
+                2.0 * (k * k - vh),
+                vh - vb * k / q + k * k,
+            ],
+            [
+                1.0 + k / q + k * k,
+                2.0 * (k * k - 1.0),
+                1.0 - k / q + k * k,
+            ],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI_F64 * f0 / fs).tan();
+        let high_pass = Biquad::from_coefficients(
+            [1.0, -2.0, 1.0],
+            [
+                1.0 + k / q + k * k,
+                2.0 * (k * k - 1.0),
+                1.0 - k / q + k * k,
+            ],
+        );
+
+        Self {
+            filters: vec![shelf, high_pass],
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.filters
+            .iter_mut()
+            .fold(sample, |sample, filter| filter.process(sample))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn gain_db(chain: &FilterChain, frequency: f32) -> f32 {
+        let gain: f32 = chain
+            .filters
+            .iter()
+            .map(|filter| filter.gain_at(frequency, SAMPLE_RATE))
+            .product();
+        20.0 * gain.log10()
+    }
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} isn't within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn a_weighting_matches_the_standard() {
+        let chain = FilterChain::a_weighting(SAMPLE_RATE);
+        assert_near(gain_db(&chain, 1000.0), 0.0, 0.01);
+        assert_near(gain_db(&chain, 100.0), -19.1, 0.2);
+        assert_near(gain_db(&chain, 2000.0), 1.2, 0.2);
+    }
+
+    #[test]
+    fn k_weighting_lifts_highs_and_cuts_lows() {
+        let chain = FilterChain::k_weighting(SAMPLE_RATE);
+        // The -0.691 dB offset in BS.1770 makes up for this
+        assert_near(gain_db(&chain, 1000.0), 0.691, 0.1);
+        assert_near(gain_db(&chain, 10000.0), 4.0, 0.2);
+        assert!(gain_db(&chain, 20.0) < -6.0);
+    }
+
+    #[test]
+    fn butterworth_filters_are_3_db_down_at_their_cutoff() {
+        for spec in [
+            FilterSpec::HighPass {
+                cutoff_hz: 300.0,
+                q: butterworth_q(),
+            },
+            FilterSpec::LowPass {
+                cutoff_hz: 5000.0,
+                q: butterworth_q(),
+            },
+        ] {
+            let chain = FilterChain::new(&[spec], SAMPLE_RATE).unwrap();
+            assert_near(gain_db(&chain, spec.frequency()), -3.01, 0.05);
+        }
+    }
+
+    #[test]
+    fn band_pass_passes_its_center() {
+        let spec = FilterSpec::BandPass {
+            center_hz: 1000.0,
+            q: 2.0,
+        };
+        let chain = FilterChain::new(&[spec], SAMPLE_RATE).unwrap();
+        assert_near(gain_db(&chain, 1000.0), 0.0, 0.05);
+        assert!(gain_db(&chain, 100.0) < -15.0);
+    }
+
+    #[test]
+    fn processing_matches_the_response() {
+        let spec = FilterSpec::HighPass {
+            cutoff_hz: 300.0,
+            q: butterworth_q(),
+        };
+        let mut chain = FilterChain::new(&[spec], SAMPLE_RATE).unwrap();
+        let expected = gain_db(&chain, 150.0);
+
+        let sine: Vec<f32> = (0..SAMPLE_RATE)
+            .map(|i| (2.0 * PI * 150.0 * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect();
+        let filtered: Vec<f32> = sine.iter().map(|&x| chain.process(x)).collect();
+        // Skip the first half while the filter settles
+        let power = |samples: &[f32]| samples.iter().map(|x| x * x).sum::<f32>();
+        let half = sine.len() / 2;
+        let measured = 10.0 * (power(&filtered[half..]) / power(&sine[half..])).log10();
+        assert_near(measured, expected, 0.1);
+    }
+}
@@ -0,0 +1,66 @@
+use serde::Serialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const HISTORY_FILE: &str = "sessions.jsonl";
+
+/// The outcome of one live session, appended to the history file as a JSON line.
+#[derive(Serialize)]
+pub struct SessionRecord {
+    /// Seconds since the Unix epoch.
+    pub finished_at: u64,
+    pub device: String,
+    pub time_limit_secs: f32,
+    pub elapsed_secs: f32,
+    /// Plaps counted in each tier, from the quietest tier up.
+    pub counts: Vec<(String, usize)>,
+    /// Impulses too weak to count as plaps.
+    pub missed: usize,
+    pub claps: Vec<ClapRecord>,
+    /// Total time the input was lost for.
+    pub lost_input_secs: f32,
+    /// Whether the session was interrupted before the time limit.
+    pub aborted: bool,
+}
+
+/// One counted plap in a session.
+#[derive(Serialize)]
+pub struct ClapRecord {
+    /// Seconds into the session.
+    pub at_secs: f32,
+    pub tier: String,
+    /// Intensity from 0 to 100.
+    pub score: f32,
+    /// Loudest level the plap reached, in dB.
+    pub peak_db: f32,
+    pub attack_ms: f32,
+    pub duration_ms: f32,
+}
+
+/// Current time in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs())
+}
+
+/// Where clapcounter keeps the files it writes.
+pub fn data_dir() -> anyhow::Result<PathBuf> {
+    let dir = dirs::data_dir()
+        .ok_or_else(|| anyhow::anyhow!("Couldn't find a data directory"))?
+        .join("clapcounter");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Appends `record` to the history file and returns its path.
+pub fn save(record: &SessionRecord) -> anyhow::Result<PathBuf> {
+    let path = data_dir()?.join(HISTORY_FILE);
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(path)
+}
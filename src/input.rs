@@ -0,0 +1,162 @@
+use crate::detector::{Analyser, DetectorConfig, Frame};
+use cpal::{
+    traits::{DeviceTrait, StreamTrait},
+    FromSample, SizedSample,
+};
+use ringbuf::{
+    traits::{Producer, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::Sender,
+    Arc,
+};
+
+/// Frames the audio callback can queue up before the session loop has to catch up.
+const FRAME_QUEUE_LENGTH: usize = 512;
+
+/// A running input stream and the frames its callback produces.
+pub struct Input {
+    stream: cpal::Stream,
+    pub frames: HeapCons<Frame>,
+    pub sample_rate: u32,
+}
+
+impl Input {
+    /// Starts analysing `device`.
+    ///
+    /// Frames that don't fit in the queue are counted in `dropped_frames`, and stream errors are
+    /// sent to `errors`.
+    pub fn open(
+        device: &cpal::Device,
+        detector_config: &DetectorConfig,
+        dropped_frames: &Arc<AtomicUsize>,
+        errors: &Sender<cpal::StreamError>,
+    ) -> anyhow::Result<Self> {
+        let config = input_config(device)?;
+        let sample_rate = config.sample_rate().0;
+        let analyser = Analyser::new(detector_config, sample_rate, config.channels() as usize)?;
+
+        let (producer, frames) = HeapRb::<Frame>::new(FRAME_QUEUE_LENGTH).split();
+        let sink = FrameSink {
+            analyser,
+            producer,
+            dropped_frames: Arc::clone(dropped_frames),
+        };
+        let errors = errors.clone();
+
+        // Run different processing based on sample format
+        let sample_format = config.sample_format();
+        let config = config.into();
+        let stream = match sample_format {
+            cpal::SampleFormat::I8 => build_stream::<i8>(device, &config, sink, errors)?,
+            cpal::SampleFormat::I16 => build_stream::<i16>(device, &config, sink, errors)?,
+            cpal::SampleFormat::I32 => build_stream::<i32>(device, &config, sink, errors)?,
+            cpal::SampleFormat::I64 => build_stream::<i64>(device, &config, sink, errors)?,
+            cpal::SampleFormat::U8 => build_stream::<u8>(device, &config, sink, errors)?,
+            cpal::SampleFormat::U16 => build_stream::<u16>(device, &config, sink, errors)?,
+            cpal::SampleFormat::U32 => build_stream::<u32>(device, &config, sink, errors)?,
+            cpal::SampleFormat::U64 => build_stream::<u64>(device, &config, sink, errors)?,
+            cpal::SampleFormat::F32 => build_stream::<f32>(device, &config, sink, errors)?,
+            cpal::SampleFormat::F64 => build_stream::<f64>(device, &config, sink, errors)?,
+            format => return Err(anyhow::anyhow!("Unsupported sample format {format}")),
+        };
+
+        stream.play()?;
+
+        Ok(Self {
+            stream,
+            frames,
+            sample_rate,
+        })
+    }
+
+    pub fn pause(&self) -> anyhow::Result<()> {
+        Ok(self.stream.pause()?)
+    }
+
+    pub fn play(&self) -> anyhow::Result<()> {
+        Ok(self.stream.play()?)
+    }
+}
+
+/// Everything the audio callback owns.
+struct FrameSink {
+    analyser: Analyser,
+    producer: HeapProd<Frame>,
+    dropped_frames: Arc<AtomicUsize>,
+}
+
+impl FrameSink {
+    fn process<T>(&mut self, data: &[T])
+    where
+        T: SizedSample,
+        f32: cpal::FromSample<T>,
+    {
+        self.analyser.process(data, |frame| {
+            if self.producer.try_push(frame).is_err() {
+                self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut sink: FrameSink,
+    errors: Sender<cpal::StreamError>,
+) -> anyhow::Result<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+    f32: cpal::FromSample<T>,
+{
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| sink.process(data),
+        move |err| {
+            // The session loop is gone if this fails, so there's no one left to tell.
+            let _ = errors.send(err);
+        },
+        None,
+    )?;
+
+    Ok(stream)
+}
+
+fn is_supported_format(format: cpal::SampleFormat) -> bool {
+    use cpal::SampleFormat::*;
+    matches!(
+        format,
+        I8 | I16 | I32 | I64 | U8 | U16 | U32 | U64 | F32 | F64
+    )
+}
+
+/// Uses the device's default input config if `Input` can handle its sample format, otherwise the
+/// best supported config that it can, keeping the default sample rate where possible.
+fn input_config(device: &cpal::Device) -> anyhow::Result<cpal::SupportedStreamConfig> {
+    let default = device.default_input_config();
+    if let Ok(config) = &default {
+        if is_supported_format(config.sample_format()) {
+            return Ok(config.clone());
+        }
+    }
+
+    let mut ranges: Vec<_> = device
+        .supported_input_configs()?
+        .filter(|range| is_supported_format(range.sample_format()))
+        .collect();
+    ranges.sort_by(|a, b| a.cmp_default_heuristics(b));
+
+    let range = ranges
+        .pop()
+        .ok_or_else(|| anyhow::anyhow!("Input device has no supported sample format"))?;
+
+    Ok(match default {
+        Ok(default) => range
+            .try_with_sample_rate(default.sample_rate())
+            .unwrap_or_else(|| range.with_max_sample_rate()),
+        Err(_) => range.with_max_sample_rate(),
+    })
+}
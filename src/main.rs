@@ -1,39 +1,230 @@
+mod analyze;
+mod calibration;
+mod cli;
+mod config;
+mod detector;
+mod filter;
+mod history;
+mod input;
+mod session;
+mod strategies;
+mod tiers;
+
+use calibration::{Measurement, SavedAction, SavedCalibration};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
 use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    FromSample, InputDevices, Sample, SizedSample,
+    traits::{DeviceTrait, HostTrait},
+    InputDevices,
 };
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use detector::DetectorConfig;
 use gag::Gag;
-use inquire::{CustomType, InquireError};
+use input::Input;
+use inquire::{CustomType, InquireError, Select};
+use ringbuf::traits::Consumer;
+use session::{print_clap, print_miss, print_noise, print_summary, AppState, Tick};
 use std::{
+    io::{self, IsTerminal},
     process,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
     time::{Duration, Instant},
 };
 
-const ACTIVE_DELAY: Duration = Duration::from_secs(1);
-const CALIBRATION_COMPLETE_DELAY: Duration = Duration::from_secs(2);
+/// How long the stream can go without producing a frame before it's treated as lost.
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-const BASELINE_WINDOW: usize = 50;
-const FREQUENCY: f32 = 50.0;
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref(), cli.profile.as_deref())?;
+    if cli.print_config {
+        return config.print();
+    }
+
+    let settings = config.settings;
+    let detector_config = cli.detector_config(&settings)?;
+    let calibration_config = cli.calibration_config(&settings);
+    let active_delay = settings.active_delay();
+    // Misses are only counted and shown if they're being looked for
+    let miss_message = (settings.miss_threshold != 0.0).then_some(settings.miss_message);
+
+    if let Some(Command::Analyze(args)) = &cli.command {
+        return analyze::analyze(
+            args,
+            &detector_config,
+            calibration_config,
+            active_delay,
+            settings.tiers,
+            miss_message,
+        );
+    }
+
+    // Audio setup
+    let host = cpal::default_host();
 
-const PEAK_THRESHOLD: f32 = 15.0;
-const RESET_THRESHOLD: f32 = 4.0;
-const RESET_DISTANCE: isize = 8;
+    if cli.list_devices {
+        print_input_devices(&host)?;
+        return Ok(());
+    }
 
-const CALIBRATION_TOLERANCE: f32 = 0.9;
+    let input_device = match &cli.device {
+        Some(query) => find_input_device(&host, query)?,
+        None if cli.yes => host
+            .default_input_device()
+            .ok_or_else(|| anyhow::anyhow!("No default input device, pass --device"))?,
+        None => prompt_input_device(&host)?,
+    };
+
+    let time_limit = match cli.time_limit {
+        Some(m) => Duration::from_secs_f32(m * 60.0),
+        None if cli.yes => return Err(anyhow::anyhow!("--time-limit is required with --yes")),
+        None => prompt_time_limit()?,
+    };
+
+    let show_claps = match cli.show_counts() {
+        Some(x) => x,
+        None if cli.yes => false,
+        None => prompt_show_claps()?,
+    };
+
+    let device_name = input_device.name()?;
+    let saved = match calibration::load(&device_name) {
+        Ok(saved) => saved,
+        Err(e) => {
+            eprintln!("Couldn't read the saved calibrations: {e}");
+            None
+        }
+    };
+
+    // Calibrations are only saved when there wasn't one or it's being refreshed
+    let mismatch = saved
+        .as_ref()
+        .and_then(|saved| saved.mismatch(&Measurement::new(&detector_config)));
+    let (reuse, save_calibration) = match (saved, mismatch) {
+        (None, _) => (None, true),
+        (Some(_), Some(reason)) => {
+            println!(
+                "The saved calibration for {device_name} was measured with {reason}, so it can't \
+                 be reused. Calibrating again."
+            );
+            (None, true)
+        }
+        (Some(saved), None) => {
+            let action = match cli.saved_calibration {
+                Some(action) => action,
+                None if cli.yes => SavedAction::Reuse,
+                None => prompt_saved_calibration(&saved)?,
+            };
+            match action {
+                SavedAction::Reuse => (Some(saved), false),
+                SavedAction::Refresh => (None, true),
+                SavedAction::Discard => {
+                    calibration::forget(&device_name)?;
+                    (None, false)
+                }
+            }
+        }
+    };
+
+    let state = |sample_rate| {
+        let mut state = AppState::new(
+            time_limit,
+            show_claps,
+            calibration_config,
+            active_delay,
+            settings.tiers,
+            miss_message,
+            sample_rate,
+        );
+        if let Some(saved) = reuse {
+            state.reuse_calibration(saved);
+        }
+        state
+    };
+    run(
+        &host,
+        input_device,
+        detector_config,
+        state,
+        settings.poll_frequency,
+        !cli.yes,
+        save_calibration,
+    )
+}
+
+fn print_input_devices(host: &cpal::Host) -> anyhow::Result<()> {
+    let names = input_device_names(host)?;
+    println!("Input devices:");
+    for (i, name) in names.iter().enumerate() {
+        println!("[{i}] {name}");
+    }
+    Ok(())
+}
+
+/// Resolves `query` as a device index, an exact device name, or a case-insensitive
+/// substring that matches exactly one device name.
+fn find_input_device(host: &cpal::Host, query: &str) -> anyhow::Result<cpal::Device> {
+    let index = match_device(&input_device_names(host)?, query)?;
 
-fn main() -> anyhow::Result<()> {
     let err_gag = Gag::stderr()?;
+    let device = get_input_devices(host)?.nth(index);
+    drop(err_gag);
 
-    // Audio setup
-    let host = cpal::default_host();
-    let input_devs = get_input_devices(&host)?;
+    device.ok_or_else(|| anyhow::anyhow!("Input device [{index}] disappeared"))
+}
+
+/// Index of the device in `names` that `query` picks out: its index, its exact name, or part of
+/// its name that no other device has.
+fn match_device(names: &[String], query: &str) -> anyhow::Result<usize> {
+    if let Ok(i) = query.parse::<usize>() {
+        if i >= names.len() {
+            return Err(anyhow::anyhow!("No input device with index {i}"));
+        }
+        return Ok(i);
+    }
+    if let Some(i) = names.iter().position(|name| name == query) {
+        return Ok(i);
+    }
+
+    let needle = query.to_lowercase();
+    let matches: Vec<usize> = names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| name.to_lowercase().contains(&needle))
+        .map(|(i, _)| i)
+        .collect();
+    match matches[..] {
+        [i] => Ok(i),
+        [] => Err(anyhow::anyhow!("No input device matches \"{query}\"")),
+        _ => {
+            let listed: Vec<String> = matches
+                .iter()
+                .map(|&i| format!("[{i}] {}", names[i]))
+                .collect();
+            Err(anyhow::anyhow!(
+                "\"{query}\" matches several input devices:\n{}",
+                listed.join("\n")
+            ))
+        }
+    }
+}
+
+fn prompt_input_device(host: &cpal::Host) -> anyhow::Result<cpal::Device> {
+    let err_gag = Gag::stderr()?;
+
+    let input_devs = get_input_devices(host)?;
     println!("Input devices:");
     for (i, dev) in input_devs.enumerate() {
         println!("[{i}] {}", dev.name()?);
     }
 
-    let input_devs = get_input_devices(&host)?;
+    let input_devs = get_input_devices(host)?;
     let default_index = host.default_input_device().and_then(|def| {
         let def_name = def.name().ok()?;
         input_devs
@@ -41,22 +232,22 @@ fn main() -> anyhow::Result<()> {
             .position(|x| x == def_name)
     });
 
-    let mut input_devs = get_input_devices(&host)?;
+    let mut input_devs = get_input_devices(host)?;
     drop(err_gag);
 
-    let input_device = loop {
+    loop {
         if let Some(default_ix) = default_index {
             match CustomType::new(&format!("Select input device: [{default_ix}]"))
                 .prompt_skippable()
             {
                 Ok(Some(i)) => {
                     if let Some(device) = input_devs.nth(i) {
-                        break device;
+                        return Ok(device);
                     }
                 }
                 Ok(None) => {
                     if let Some(device) = input_devs.nth(default_ix) {
-                        break device;
+                        return Ok(device);
                     }
                 }
                 Err(InquireError::OperationInterrupted) => {
@@ -68,7 +259,7 @@ fn main() -> anyhow::Result<()> {
             match inquire::prompt_usize("Select input device:") {
                 Ok(i) => {
                     if let Some(device) = input_devs.nth(i) {
-                        break device;
+                        return Ok(device);
                     }
                 }
                 Err(InquireError::OperationInterrupted) => {
@@ -77,276 +268,374 @@ fn main() -> anyhow::Result<()> {
                 Err(e) => return Err(e.into()),
             }
         }
-    };
+    }
+}
 
-    let time_limit = loop {
+fn prompt_time_limit() -> anyhow::Result<Duration> {
+    loop {
         match inquire::prompt_f32("Time limit (mins):") {
-            Ok(m) if m > 0.0 => break Duration::from_secs_f32(m * 60.0),
+            Ok(m) if m > 0.0 && m <= cli::MAX_MINUTES => {
+                return Ok(Duration::from_secs_f32(m * 60.0))
+            }
             Ok(_) => {}
             Err(InquireError::OperationInterrupted) => {
                 process::exit(0);
             }
             Err(e) => return Err(e.into()),
         }
-    };
+    }
+}
 
-    let show_claps = {
-        match inquire::prompt_confirmation("Show plap counts after each plap? (y/n):") {
-            Ok(x) => x,
-            Err(InquireError::OperationInterrupted) => {
-                process::exit(0);
-            }
-            Err(e) => return Err(e.into()),
+fn prompt_show_claps() -> anyhow::Result<bool> {
+    match inquire::prompt_confirmation("Show plap counts after each plap? (y/n):") {
+        Ok(x) => Ok(x),
+        Err(InquireError::OperationInterrupted) => {
+            process::exit(0);
         }
-    };
+        Err(e) => Err(e.into()),
+    }
+}
 
-    let state = AppState::new(time_limit, show_claps);
-    let state = Arc::new(Mutex::new(state));
+fn prompt_saved_calibration(saved: &SavedCalibration) -> anyhow::Result<SavedAction> {
+    let options = vec![
+        "Reuse it",
+        "Calibrate again and replace it",
+        "Calibrate again and forget it",
+    ];
+    let message = format!(
+        "Found a calibration for this device from {} ago:",
+        saved.age()
+    );
+    match Select::new(&message, options).raw_prompt() {
+        Ok(choice) => Ok(match choice.index {
+            0 => SavedAction::Reuse,
+            1 => SavedAction::Refresh,
+            _ => SavedAction::Discard,
+        }),
+        Err(InquireError::OperationInterrupted) => {
+            process::exit(0);
+        }
+        Err(e) => Err(e.into()),
+    }
+}
 
-    let config = input_device.default_input_config()?;
+fn prompt_retry_calibration() -> anyhow::Result<bool> {
+    match inquire::prompt_confirmation("Try calibrating again? (y/n):") {
+        Ok(x) => Ok(x),
+        Err(InquireError::OperationCanceled | InquireError::OperationInterrupted) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    // Run different processing based on sample format
-    match config.sample_format() {
-        cpal::SampleFormat::F32 => run::<f32>(input_device, config.into(), state)?,
-        cpal::SampleFormat::I16 => run::<i16>(input_device, config.into(), state)?,
-        cpal::SampleFormat::U16 => run::<u16>(input_device, config.into(), state)?,
-        _ => return Err(anyhow::anyhow!("Unsupported sample format")),
+/// Asks whether to calibrate again after a failed attempt, with the input paused meanwhile, and
+/// goes back to the previous calibration if not. Returns whether the session carries on.
+fn retry_calibration(input: &Input, state: &mut AppState) -> anyhow::Result<bool> {
+    input.pause()?;
+    if !prompt_retry_calibration()? && !state.restore_calibration() {
+        return Ok(false);
     }
+    input.play()?;
+    Ok(true)
+}
 
-    Ok(())
+/// Whether the recalibration key was pressed since the last check. Without raw mode the key only
+/// comes through once Enter is pressed after it.
+fn recalibration_requested() -> bool {
+    let mut requested = false;
+    while let Ok(true) = event::poll(Duration::ZERO) {
+        match event::read() {
+            Ok(Event::Key(KeyEvent {
+                code: KeyCode::Char('r' | 'R'),
+                kind: KeyEventKind::Press,
+                ..
+            })) => requested = true,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    requested
 }
 
-fn run<T>(
+fn run(
+    host: &cpal::Host,
     device: cpal::Device,
-    config: cpal::StreamConfig,
-    state: Arc<Mutex<AppState>>,
-) -> anyhow::Result<()>
-where
-    T: SizedSample + FromSample<f32>,
-    f32: cpal::FromSample<T>,
-{
-    let err_fn = |err| eprintln!("Error in audio stream: {}", err);
-
-    let stream = device.build_input_stream(
-        &config,
-        {
-            let state = Arc::clone(&state);
-            move |data: &[T], _: &cpal::InputCallbackInfo| {
-                process_audio(data, &state);
-            }
-        },
-        err_fn,
-        None,
-    )?;
-
-    stream.play()?;
+    detector_config: DetectorConfig,
+    state: impl FnOnce(u32) -> AppState,
+    poll_frequency: f32,
+    interactive: bool,
+    save_calibration: bool,
+) -> anyhow::Result<()> {
+    let device_name = device.name()?;
+    let dropped_frames = Arc::new(AtomicUsize::new(0));
+    let (error_sender, errors) = mpsc::channel();
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let interrupted = Arc::clone(&interrupted);
+        move || interrupted.store(true, Ordering::Relaxed)
+    })?;
+
+    let mut input = Input::open(&device, &detector_config, &dropped_frames, &error_sender)?;
+    let mut state = state(input.sample_rate);
+
+    let stall_timeout = STALL_TIMEOUT + Duration::from_secs_f32(detector_config.frame_ms / 1000.0);
+    let mut last_frame_at = Instant::now();
+    let mut reported_dropped = 0;
+
+    let keys = io::stdin().is_terminal();
+    if keys {
+        println!("Type r and press Enter at any time to recalibrate.");
+    }
 
     loop {
-        std::thread::sleep(Duration::from_secs_f32(1.0 / FREQUENCY));
-
-        let mut state_lock = state.lock().unwrap();
+        std::thread::sleep(Duration::from_secs_f32(1.0 / poll_frequency));
+
+        if interrupted.load(Ordering::Relaxed) {
+            drop(input);
+            finish(
+                &state,
+                &device_name,
+                dropped_frames.load(Ordering::Relaxed),
+                true,
+            );
+            return Ok(());
+        }
 
-        if !state_lock.is_active() {
-            continue;
+        if keys && recalibration_requested() {
+            state.recalibrate();
         }
 
-        if !state_lock.calibrate() {
-            continue;
+        let dropped = dropped_frames.load(Ordering::Relaxed);
+        if dropped > reported_dropped {
+            eprintln!("Falling behind the audio stream, {dropped} frames dropped so far");
+            reported_dropped = dropped;
         }
 
-        let elapsed = Instant::now().duration_since(state_lock.timer_started);
-        let Some(remaining) = state_lock.time_limit.checked_sub(elapsed) else {
-            println!("Times up!");
-            println!(
-                "Hard plaps: {}        Soft plaps: {}",
-                state_lock.hard_claps, state_lock.soft_claps
-            );
-            return Ok(());
-        };
+        while let Some(frame) = input.frames.try_pop() {
+            last_frame_at = Instant::now();
+
+            match state.tick(&frame) {
+                Tick::Idle => {}
+                Tick::Clap {
+                    tier, remaining, ..
+                } => print_clap(&state, "", tier, remaining),
+                Tick::Miss { remaining, .. } => print_miss(&state, "", remaining),
+                Tick::Calibrated(calibration) => {
+                    if save_calibration {
+                        let saved = SavedCalibration::new(
+                            calibration,
+                            Measurement::new(&detector_config),
+                            state.calibration_config.tolerance,
+                            state.sample_rate,
+                        );
+                        match calibration::save(&device_name, saved) {
+                            Ok(()) => println!("Calibration saved for {device_name}."),
+                            Err(e) => eprintln!("Couldn't save the calibration: {e}"),
+                        }
+                    }
+                }
+                Tick::CalibrationFailed(failure) => {
+                    println!("Calibration failed: {failure}.");
+                    // Without anyone to ask, a failed recalibration falls back on the previous
+                    // calibration rather than holding the timer until one works
+                    if !interactive {
+                        state.restore_calibration();
+                        continue;
+                    }
 
-        let hard_threshold = state_lock.last_calibrate_max
-            - (state_lock.last_calibrate_max - state_lock.baseline) * (1.0 - CALIBRATION_TOLERANCE);
-
-        if state_lock.detect_peak() {
-            let total_secs = remaining.as_secs();
-            let mins = total_secs / 60;
-            let secs = total_secs % 60;
-
-            if state_lock.current_db >= hard_threshold {
-                state_lock.hard_claps += 1;
-                if state_lock.show_claps {
-                    println!("Good girl~!           Hard plaps: {}      Soft plaps: {}      Time remaining: {:02}:{:02}", state_lock.hard_claps, state_lock.soft_claps, mins, secs);
-                } else {
-                    println!(
-                        "Good girl~!           Time remaining: {:02}:{:02}",
-                        mins, secs
-                    );
+                    // Errors here still finish the session, so the counts so far aren't lost
+                    match retry_calibration(&input, &mut state) {
+                        Ok(true) => last_frame_at = Instant::now(),
+                        Ok(false) => {
+                            drop(input);
+                            finish(
+                                &state,
+                                &device_name,
+                                dropped_frames.load(Ordering::Relaxed),
+                                true,
+                            );
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            drop(input);
+                            finish(
+                                &state,
+                                &device_name,
+                                dropped_frames.load(Ordering::Relaxed),
+                                true,
+                            );
+                            return Err(e);
+                        }
+                    }
                 }
-            } else {
-                state_lock.soft_claps += 1;
-                if state_lock.show_claps {
-                    println!("Worthless paypig!     Hard plaps: {}      Soft plaps: {}      Time remaining: {:02}:{:02}", state_lock.hard_claps, state_lock.soft_claps, mins, secs);
-                } else {
-                    println!(
-                        "Worthless paypig!     Time remaining: {:02}:{:02}",
-                        mins, secs
+                Tick::Noise(noise) => print_noise(noise),
+                Tick::TimesUp => {
+                    drop(input);
+                    finish(
+                        &state,
+                        &device_name,
+                        dropped_frames.load(Ordering::Relaxed),
+                        false,
                     );
+                    return Ok(());
                 }
             }
         }
-    }
-}
 
-fn process_audio<T>(data: &[T], state: &Arc<Mutex<AppState>>)
-where
-    T: Sample + FromSample<f32>,
-    f32: cpal::FromSample<T>,
-{
-    let mut state_lock = state.lock().unwrap();
+        let lost = match errors.try_recv() {
+            Ok(err) => Some(err.to_string()),
+            Err(_) if last_frame_at.elapsed() > stall_timeout => {
+                Some("no audio is coming in".to_owned())
+            }
+            Err(_) => None,
+        };
 
-    // Calculate RMS (root mean square) of the audio buffer
-    let sum_squares: f32 = data
-        .iter()
-        .map(|s| {
-            let sample: f32 = s.to_sample();
-            sample * sample
-        })
-        .sum();
+        if let Some(reason) = lost {
+            println!("Lost the input device ({reason}), timer paused.");
+            drop(input);
+
+            let lost_at = Instant::now();
+            let Some(reconnected) = reconnect(
+                host,
+                &device_name,
+                &detector_config,
+                &dropped_frames,
+                &error_sender,
+                &interrupted,
+            ) else {
+                state.resume(state.sample_rate, lost_at.elapsed());
+                finish(
+                    &state,
+                    &device_name,
+                    dropped_frames.load(Ordering::Relaxed),
+                    true,
+                );
+                return Ok(());
+            };
+            input = reconnected;
+            while errors.try_recv().is_ok() {}
+
+            state.resume(input.sample_rate, lost_at.elapsed());
+            last_frame_at = Instant::now();
+            println!("Input is back, timer resumed.");
+        }
+    }
+}
 
-    let rms = (sum_squares / data.len() as f32).sqrt();
+/// Keeps trying to reopen the device called `device_name`, falling back to the default input
+/// device once it has been gone for `RECONNECT_TIMEOUT`. Gives up if `interrupted` gets set.
+fn reconnect(
+    host: &cpal::Host,
+    device_name: &str,
+    detector_config: &DetectorConfig,
+    dropped_frames: &Arc<AtomicUsize>,
+    errors: &mpsc::Sender<cpal::StreamError>,
+    interrupted: &AtomicBool,
+) -> Option<Input> {
+    let started = Instant::now();
 
-    let db = if rms > 0.0 {
-        20.0 * rms.log10()
-    } else {
-        f32::NEG_INFINITY
-    };
+    loop {
+        std::thread::sleep(RECONNECT_INTERVAL);
 
-    state_lock.current_db = db;
+        if interrupted.load(Ordering::Relaxed) {
+            return None;
+        }
 
-    if state_lock.baseline == 0.0 {
-        state_lock.baseline = db;
+        let Ok(err_gag) = Gag::stderr() else {
+            continue;
+        };
+        let device = find_input_device_by_name(host, device_name).or_else(|| {
+            if started.elapsed() > RECONNECT_TIMEOUT {
+                host.default_input_device()
+            } else {
+                None
+            }
+        });
+        let input = device.and_then(|device| {
+            let input = Input::open(&device, detector_config, dropped_frames, errors).ok()?;
+            Some((input, device.name().unwrap_or_default()))
+        });
+        drop(err_gag);
+
+        if let Some((input, name)) = input {
+            if name != device_name {
+                println!("Switched to the default input device, {name}.");
+            }
+            return Some(input);
+        }
     }
+}
 
-    if state_lock.baseline_samples < BASELINE_WINDOW {
-        state_lock.baseline_samples += 1;
-        state_lock.baseline_sum += db;
-        state_lock.baseline = state_lock.baseline_sum / state_lock.baseline_samples as f32;
+/// Prints the summary of a live session and adds it to the history.
+fn finish(state: &AppState, device_name: &str, dropped_frames: usize, aborted: bool) {
+    if aborted {
+        let secs = state.elapsed().as_secs();
+        println!("Session aborted after {:02}:{:02}.", secs / 60, secs % 60);
     } else {
-        state_lock.baseline =
-            (state_lock.baseline * (BASELINE_WINDOW - 1) as f32 + db) / BASELINE_WINDOW as f32;
+        println!("Times up!");
+    }
+    print_summary(state);
+    if dropped_frames > 0 {
+        println!("Dropped frames: {dropped_frames}");
     }
-}
 
-struct AppState {
-    current_db: f32,
-    baseline: f32,
-    baseline_samples: usize,
-    baseline_sum: f32,
-    last_peak_distance: isize,
-    started: Instant,
-    last_calibrate_max: f32,
-    calibration: CalibrationStatus,
-    timer_started: Instant,
-    time_limit: Duration,
-    show_claps: bool,
-    hard_claps: usize,
-    soft_claps: usize,
+    let record = state.record(device_name, aborted);
+    match history::save(&record) {
+        Ok(path) => println!("Session saved to {}", path.display()),
+        Err(e) => eprintln!("Couldn't save the session: {e}"),
+    }
 }
 
-enum CalibrationStatus {
-    Waiting,
-    Started { last_max_instant: Instant },
-    Complete,
+fn get_input_devices<H: HostTrait>(host: &H) -> anyhow::Result<InputDevices<H::Devices>> {
+    Ok(host.input_devices()?)
 }
 
-impl AppState {
-    fn new(time_limit: Duration, show_claps: bool) -> Self {
-        Self {
-            current_db: 0.0,
-            baseline: 0.0,
-            baseline_samples: 0,
-            baseline_sum: 0.0,
-            last_peak_distance: -1,
-            started: Instant::now(),
-            last_calibrate_max: f32::NEG_INFINITY,
-            calibration: CalibrationStatus::Waiting,
-            timer_started: Instant::now(),
-            time_limit,
-            show_claps,
-            hard_claps: 0,
-            soft_claps: 0,
-        }
-    }
+fn find_input_device_by_name(host: &cpal::Host, name: &str) -> Option<cpal::Device> {
+    get_input_devices(host)
+        .ok()?
+        .find(|dev| dev.name().is_ok_and(|dev_name| dev_name == name))
+}
 
-    fn is_active(&self) -> bool {
-        Instant::now().duration_since(self.started) > ACTIVE_DELAY
-    }
+fn input_device_names(host: &cpal::Host) -> anyhow::Result<Vec<String>> {
+    let err_gag = Gag::stderr()?;
+    let names = get_input_devices(host)?
+        .map(|dev| dev.name())
+        .collect::<Result<_, _>>()?;
+    drop(err_gag);
+    Ok(names)
+}
 
-    fn calibrate(&mut self) -> bool {
-        match self.calibration {
-            CalibrationStatus::Waiting => {
-                println!("Beginning calibration, plap HARD!");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                self.calibration = CalibrationStatus::Started {
-                    last_max_instant: Instant::now(),
-                };
-                false
-            }
-            CalibrationStatus::Started { last_max_instant }
-                if Instant::now().duration_since(last_max_instant) > CALIBRATION_COMPLETE_DELAY =>
-            {
-                println!("Calibration is complete, timer has started.");
-                self.calibration = CalibrationStatus::Complete;
-                self.timer_started = Instant::now();
-                true
-            }
-            CalibrationStatus::Started {
-                ref mut last_max_instant,
-            } => {
-                if self.current_db > self.last_calibrate_max {
-                    self.last_calibrate_max = self.current_db;
-                    *last_max_instant = Instant::now();
-                }
-                false
-            }
-            CalibrationStatus::Complete => true,
-        }
+    fn names() -> Vec<String> {
+        ["USB Mic", "usb mic (2)", "Built-in Microphone", "Webcam"]
+            .map(str::to_owned)
+            .to_vec()
     }
 
-    fn peak_threshold(&self) -> f32 {
-        self.baseline + PEAK_THRESHOLD
+    #[test]
+    fn matches_device_by_index() {
+        assert_eq!(match_device(&names(), "2").unwrap(), 2);
+        assert!(match_device(&names(), "4").is_err());
     }
 
-    fn reset_threshold(&self) -> f32 {
-        self.peak_threshold() - RESET_THRESHOLD
+    #[test]
+    fn exact_name_beats_substring() {
+        // "USB Mic" is also part of "usb mic (2)" once case is ignored
+        assert_eq!(match_device(&names(), "USB Mic").unwrap(), 0);
     }
 
-    fn detect_peak(&mut self) -> bool {
-        if self.last_peak_distance != -1 {
-            self.reset_peak();
-            return false;
-        }
-
-        if self.current_db > self.peak_threshold() {
-            self.last_peak_distance = 0;
-            true
-        } else {
-            false
-        }
+    #[test]
+    fn matches_unique_substring_ignoring_case() {
+        assert_eq!(match_device(&names(), "webcam").unwrap(), 3);
+        assert_eq!(match_device(&names(), "BUILT-IN").unwrap(), 2);
     }
 
-    fn reset_peak(&mut self) {
-        if self.last_peak_distance == -1 {
-            return;
-        }
-
-        self.last_peak_distance += 1;
-        if self.last_peak_distance > RESET_DISTANCE && self.current_db < self.reset_threshold() {
-            self.last_peak_distance = -1;
-        }
+    #[test]
+    fn rejects_ambiguous_or_unknown_queries() {
+        assert!(match_device(&names(), "mic").is_err());
+        assert!(match_device(&names(), "headset").is_err());
     }
 }
-
-fn get_input_devices<H: HostTrait>(host: &H) -> anyhow::Result<InputDevices<H::Devices>> {
-    Ok(host.input_devices()?)
-}
@@ -0,0 +1,622 @@
+use crate::{
+    calibration::{Calibration, CalibrationConfig, Calibrator, Failure, SavedCalibration},
+    detector::{Frame, Noise, Onset},
+    history::{self, ClapRecord, SessionRecord},
+    tiers::{self, Tier},
+};
+use std::time::Duration;
+
+/// Prints the feedback line for a plap, starting with `prefix`.
+pub fn print_clap(state: &AppState, prefix: &str, tier: usize, remaining: Duration) {
+    let tier = &state.tiers[tier];
+    let message = match &tier.message {
+        Some(message) => message.clone(),
+        None => format!("{}!", tier.title()),
+    };
+    print_feedback(state, prefix, &message, remaining);
+}
+
+/// Prints the feedback line for a miss, starting with `prefix`, unless the miss message is empty.
+pub fn print_miss(state: &AppState, prefix: &str, remaining: Duration) {
+    match &state.miss_message {
+        Some(message) if !message.is_empty() => print_feedback(state, prefix, message, remaining),
+        _ => {}
+    }
+}
+
+fn print_feedback(state: &AppState, prefix: &str, message: &str, remaining: Duration) {
+    let total_secs = remaining.as_secs();
+    let mins = total_secs / 60;
+    let secs = total_secs % 60;
+
+    // Pad to the longest message so the columns after it line up
+    let width = state
+        .tiers
+        .iter()
+        .map(|tier| {
+            tier.message
+                .as_ref()
+                .map_or(tier.name.len() + 1, String::len)
+        })
+        .chain(state.miss_message.as_ref().map(String::len))
+        .max()
+        .unwrap_or(0);
+
+    if state.show_claps {
+        println!(
+            "{prefix}{message:width$}     {}      Time remaining: {:02}:{:02}",
+            format_counts(state, "      "),
+            mins,
+            secs
+        );
+    } else {
+        println!(
+            "{prefix}{message:width$}     Time remaining: {:02}:{:02}",
+            mins, secs
+        );
+    }
+}
+
+/// "Hard plaps: 5", "Soft plaps: 3" and so on for every tier from the loudest down, joined by
+/// `separator`.
+fn format_counts(state: &AppState, separator: &str) -> String {
+    state
+        .tiers
+        .iter()
+        .zip(&state.counts)
+        .rev()
+        .map(|(tier, count)| format!("{} plaps: {count}", tier.title()))
+        .chain(
+            state
+                .miss_message
+                .is_some()
+                .then(|| format!("Missed: {}", state.misses)),
+        )
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+pub fn print_noise(noise: Noise) {
+    match noise {
+        Noise::TooLoud => println!("Background noise too loud, not counting it."),
+        Noise::Settled => println!("Background noise has settled, listening again."),
+        Noise::Adapted => println!("Background noise hasn't died down, listening again over it."),
+    }
+}
+
+pub fn print_summary(state: &AppState) {
+    println!("{}", format_counts(state, "        "));
+    if !state.events.is_empty() {
+        let total: f32 = state.events.iter().map(|(_, event)| event.score).sum();
+        println!(
+            "Average intensity: {:.0}/100",
+            total / state.events.len() as f32
+        );
+    }
+
+    for gap in &state.gaps {
+        let at = gap.at.as_secs();
+        println!(
+            "Input lost at {:02}:{:02} for {:.1}s",
+            at / 60,
+            at % 60,
+            gap.length.as_secs_f32()
+        );
+    }
+}
+
+/// Session state, advanced by the frames coming out of the `Analyser`.
+pub struct AppState {
+    pub sample_rate: u32,
+    /// Time the current stream started at; earlier streams were lost and reopened.
+    stream_started: Duration,
+    /// Time at the end of the most recent frame.
+    now: Duration,
+    /// How long each new stream is ignored for while the detector settles.
+    active_delay: Duration,
+    /// Frames before this are ignored while the detector settles on a new stream.
+    active_from: Duration,
+    pub calibration_config: CalibrationConfig,
+    calibration: CalibrationStatus,
+    /// Calibration to go back to if recalibrating doesn't work out.
+    previous_calibration: Option<Calibration>,
+    timer_started: Duration,
+    /// Time counted before the timer was last paused for recalibration.
+    elapsed_before: Duration,
+    time_limit: Duration,
+    show_claps: bool,
+    tiers: Vec<Tier>,
+    /// Plaps counted in each tier.
+    counts: Vec<usize>,
+    /// What to show for misses, or `None` if they aren't being looked for.
+    miss_message: Option<String>,
+    misses: usize,
+    /// Every counted plap and its tier, in order.
+    events: Vec<(usize, Plap)>,
+    gaps: Vec<Gap>,
+}
+
+/// A stretch of time the input was lost for, with the timer paused.
+struct Gap {
+    /// Time into the session the input was lost at.
+    at: Duration,
+    length: Duration,
+}
+
+enum CalibrationStatus {
+    Waiting,
+    Started(Calibrator),
+    /// A calibration from an earlier session, used once the stream has settled.
+    Saved(SavedCalibration),
+    Complete(Calibration),
+}
+
+pub enum Tick {
+    Idle,
+    Clap {
+        /// Index of the tier the plap fell in.
+        tier: usize,
+        event: Plap,
+        remaining: Duration,
+    },
+    /// An impulse that was too weak to count.
+    Miss {
+        event: Plap,
+        remaining: Duration,
+    },
+    /// Calibration has just finished with this result.
+    Calibrated(Calibration),
+    CalibrationFailed(Failure),
+    Noise(Noise),
+    TimesUp,
+}
+
+/// The shape of a counted plap's envelope.
+#[derive(Clone, Copy)]
+pub struct Plap {
+    /// Time of the onset since the start of the session.
+    pub at: Duration,
+    /// Loudest level the plap reached, in dB.
+    pub peak_db: f32,
+    /// Intensity from 0 to 100, relative to the calibrated hard plaps.
+    pub score: f32,
+    /// Time from the onset to the loudest sample.
+    pub attack: Duration,
+    /// Time from the onset until the level fell off again.
+    pub duration: Duration,
+}
+
+impl AppState {
+    pub fn new(
+        time_limit: Duration,
+        show_claps: bool,
+        calibration_config: CalibrationConfig,
+        active_delay: Duration,
+        tiers: Vec<Tier>,
+        miss_message: Option<String>,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            sample_rate,
+            stream_started: Duration::ZERO,
+            now: Duration::ZERO,
+            active_delay,
+            active_from: active_delay,
+            calibration_config,
+            calibration: CalibrationStatus::Waiting,
+            previous_calibration: None,
+            timer_started: Duration::ZERO,
+            elapsed_before: Duration::ZERO,
+            time_limit,
+            show_claps,
+            counts: vec![0; tiers.len()],
+            tiers,
+            miss_message,
+            misses: 0,
+            events: Vec::new(),
+            gaps: Vec::new(),
+        }
+    }
+
+    /// Skips calibration in favour of one saved by an earlier session.
+    pub fn reuse_calibration(&mut self, saved: SavedCalibration) {
+        self.calibration = CalibrationStatus::Saved(saved);
+    }
+
+    /// Pauses the timer and calibrates again, keeping the counts and the time left.
+    pub fn recalibrate(&mut self) {
+        if let CalibrationStatus::Complete(calibration) = self.calibration {
+            println!("Recalibrating, timer paused.");
+            self.elapsed_before = self.elapsed();
+            self.previous_calibration = Some(calibration);
+            self.calibration = CalibrationStatus::Waiting;
+        }
+    }
+
+    /// Goes back to the calibration from before recalibrating and resumes the timer. Returns
+    /// whether there was one to go back to.
+    pub fn restore_calibration(&mut self) -> bool {
+        let Some(calibration) = self.previous_calibration.take() else {
+            return false;
+        };
+        println!("Keeping the previous calibration, timer has resumed.");
+        self.calibration = CalibrationStatus::Complete(calibration);
+        self.timer_started = self.now;
+        true
+    }
+
+    /// Carries on from where the previous stream left off with a newly opened one, after the
+    /// input was gone for `gap`.
+    pub fn resume(&mut self, sample_rate: u32, gap: Duration) {
+        self.gaps.push(Gap {
+            at: self.elapsed(),
+            length: gap,
+        });
+        self.sample_rate = sample_rate;
+        self.stream_started = self.now;
+        self.active_from = self.now + self.active_delay;
+    }
+
+    /// Converts a sample position in the current stream into session time.
+    pub fn time_at(&self, position: u64) -> Duration {
+        self.stream_started + Duration::from_secs_f64(position as f64 / self.sample_rate as f64)
+    }
+
+    /// Time counted towards the time limit so far.
+    pub fn elapsed(&self) -> Duration {
+        match self.calibration {
+            CalibrationStatus::Complete(_) => {
+                self.elapsed_before + self.now.saturating_sub(self.timer_started)
+            }
+            _ => self.elapsed_before,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.now > self.active_from
+    }
+
+    /// Runs calibration on the latest frame, returning the result once it's done. A failed
+    /// attempt starts over on the next frame.
+    fn calibrate(&mut self, frame: &Frame) -> Result<Option<Calibration>, Failure> {
+        let now = self.now;
+        match &mut self.calibration {
+            CalibrationStatus::Waiting => {
+                let calibrator = Calibrator::start(&self.calibration_config, now);
+                self.calibration = CalibrationStatus::Started(calibrator);
+                Ok(None)
+            }
+            CalibrationStatus::Started(calibrator) => match calibrator.update(frame, now) {
+                None => Ok(None),
+                Some(Ok(calibration)) => {
+                    if self.elapsed_before.is_zero() {
+                        println!("Calibration is complete, timer has started.");
+                    } else {
+                        println!("Calibration is complete, timer has resumed.");
+                    }
+                    self.calibration = CalibrationStatus::Complete(calibration);
+                    self.previous_calibration = None;
+                    self.timer_started = now;
+                    Ok(Some(calibration))
+                }
+                Some(Err(failure)) => {
+                    self.calibration = CalibrationStatus::Waiting;
+                    Err(failure)
+                }
+            },
+            CalibrationStatus::Saved(saved) => {
+                saved.warn_if_stale(
+                    frame.loudness_floor,
+                    self.sample_rate,
+                    self.calibration_config.tolerance,
+                );
+                println!("Using the saved calibration, timer has started.");
+                let calibration = saved.calibration;
+                self.calibration = CalibrationStatus::Complete(calibration);
+                self.timer_started = now;
+                Ok(Some(calibration))
+            }
+            CalibrationStatus::Complete(calibration) => Ok(Some(*calibration)),
+        }
+    }
+
+    /// The session so far, as it goes into the history.
+    pub fn record(&self, device: &str, aborted: bool) -> SessionRecord {
+        SessionRecord {
+            finished_at: history::unix_time(),
+            device: device.to_owned(),
+            time_limit_secs: self.time_limit.as_secs_f32(),
+            elapsed_secs: self.elapsed().as_secs_f32(),
+            counts: self
+                .tiers
+                .iter()
+                .zip(&self.counts)
+                .map(|(tier, &count)| (tier.name.clone(), count))
+                .collect(),
+            missed: self.misses,
+            claps: self
+                .events
+                .iter()
+                .map(|&(tier, event)| ClapRecord {
+                    at_secs: event.at.as_secs_f32(),
+                    tier: self.tiers[tier].name.clone(),
+                    score: event.score,
+                    peak_db: event.peak_db,
+                    attack_ms: event.attack.as_secs_f32() * 1000.0,
+                    duration_ms: event.duration.as_secs_f32() * 1000.0,
+                })
+                .collect(),
+            lost_input_secs: self.gaps.iter().map(|gap| gap.length.as_secs_f32()).sum(),
+            aborted,
+        }
+    }
+
+    /// Measures `onset` in session time and against `calibration`.
+    fn plap(&self, onset: &Onset, calibration: &Calibration, baseline: f32) -> Plap {
+        let at = self.time_at(onset.position);
+        Plap {
+            at,
+            peak_db: onset.loudness,
+            score: calibration.score(onset.loudness, baseline),
+            attack: self.time_at(onset.peak_at).saturating_sub(at),
+            duration: self.time_at(onset.end).saturating_sub(at),
+        }
+    }
+
+    /// Advances the session by one analysis frame.
+    pub fn tick(&mut self, frame: &Frame) -> Tick {
+        self.now = self.time_at(frame.end);
+
+        if !self.is_active() {
+            return Tick::Idle;
+        }
+
+        if let Some(noise) = frame.noise {
+            return Tick::Noise(noise);
+        }
+
+        let calibrating = matches!(self.calibration, CalibrationStatus::Started(_));
+        let calibration = match self.calibrate(frame) {
+            Ok(Some(calibration)) if calibrating => return Tick::Calibrated(calibration),
+            Ok(Some(calibration)) => calibration,
+            Ok(None) => return Tick::Idle,
+            Err(failure) => return Tick::CalibrationFailed(failure),
+        };
+
+        let Some(remaining) = self.time_limit.checked_sub(self.elapsed()) else {
+            return Tick::TimesUp;
+        };
+
+        let hard_threshold =
+            calibration.hard_threshold(frame.loudness_floor, self.calibration_config.tolerance);
+
+        if let Some(miss) = frame.miss {
+            self.misses += 1;
+            return Tick::Miss {
+                event: self.plap(&miss, &calibration, frame.loudness_floor),
+                remaining,
+            };
+        }
+        let Some(onset) = frame.onset else {
+            return Tick::Idle;
+        };
+
+        let event = self.plap(&onset, &calibration, frame.loudness_floor);
+        let tier = tiers::classify(&self.tiers, event.peak_db - hard_threshold);
+        self.counts[tier] += 1;
+        self.events.push((tier, event));
+        Tick::Clap {
+            tier,
+            event,
+            remaining,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::Cli, config::Settings, detector::Analyser, strategies::DetectorKind};
+    use clap::Parser;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const NOISE_FLOOR: f32 = 0.003;
+    const HARD: f32 = 0.5;
+
+    /// Synthetic recording: a quiet noise floor with plaps and other sounds laid over it.
+    struct Signal {
+        samples: Vec<f32>,
+        seed: u32,
+    }
+
+    impl Signal {
+        fn new() -> Self {
+            Self {
+                samples: Vec::new(),
+                seed: 1,
+            }
+        }
+
+        /// Gaussian noise, so that levels are repeatable from run to run.
+        fn gaussian(&mut self) -> f32 {
+            let mut uniform = || {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+                (self.seed as f32 + 1.0) / (u32::MAX as f32 + 2.0)
+            };
+            let (u, v) = (uniform(), uniform());
+            (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos()
+        }
+
+        fn samples(secs: f32) -> usize {
+            (secs * SAMPLE_RATE as f32) as usize
+        }
+
+        fn quiet(&mut self, secs: f32) -> &mut Self {
+            for _ in 0..Self::samples(secs) {
+                let noise = NOISE_FLOOR * self.gaussian();
+                self.samples.push(noise);
+            }
+            self
+        }
+
+        /// A burst of noise decaying like a plap, followed by quiet until `secs` have passed.
+        fn plap(&mut self, amplitude: f32, secs: f32) -> &mut Self {
+            for i in 0..Self::samples(secs) {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let burst = amplitude * (-t / 0.006).exp() * self.gaussian();
+                let noise = NOISE_FLOOR * self.gaussian();
+                self.samples.push(burst + noise);
+            }
+            self
+        }
+
+        /// Loud steady noise for `secs`.
+        fn noise(&mut self, amplitude: f32, secs: f32) -> &mut Self {
+            for _ in 0..Self::samples(secs) {
+                let noise = amplitude * self.gaussian();
+                self.samples.push(noise);
+            }
+            self
+        }
+
+        /// Lead-in for the detector to settle, then five hard plaps to calibrate on and long
+        /// enough a pause for calibration to finish.
+        fn calibrated() -> Self {
+            let mut signal = Self::new();
+            signal.quiet(1.5);
+            for _ in 0..5 {
+                signal.plap(HARD, 0.75);
+            }
+            signal.quiet(2.5);
+            signal
+        }
+    }
+
+    /// Runs `signal` through the analyser and a session with the default settings, returning the
+    /// session and every tick that wasn't idle.
+    fn run(signal: &Signal) -> (AppState, Vec<Tick>) {
+        run_with(signal, Settings::default())
+    }
+
+    fn run_with(signal: &Signal, settings: Settings) -> (AppState, Vec<Tick>) {
+        let cli = Cli::parse_from(["clapcounter"]);
+        let mut analyser =
+            Analyser::new(&cli.detector_config(&settings).unwrap(), SAMPLE_RATE, 1).unwrap();
+        let mut state = AppState::new(
+            Duration::from_secs(600),
+            false,
+            cli.calibration_config(&settings),
+            settings.active_delay(),
+            settings.tiers,
+            Some(settings.miss_message),
+            SAMPLE_RATE,
+        );
+
+        let mut frames = Vec::new();
+        analyser.process(&signal.samples, |frame| frames.push(frame));
+        let ticks = frames
+            .iter()
+            .map(|frame| state.tick(frame))
+            .filter(|tick| !matches!(tick, Tick::Idle))
+            .collect();
+        (state, ticks)
+    }
+
+    #[test]
+    fn calibrates_on_hard_plaps() {
+        let (state, ticks) = run(&Signal::calibrated());
+        assert!(matches!(ticks[..], [Tick::Calibrated(_)]));
+        assert_eq!(state.counts, [0, 0]);
+    }
+
+    #[test]
+    fn counts_hard_and_soft_plaps() {
+        let mut signal = Signal::calibrated();
+        signal
+            .plap(HARD, 0.75)
+            .plap(HARD / 5.0, 0.75)
+            .plap(HARD, 0.75)
+            .plap(HARD / 5.0, 0.75)
+            .plap(HARD / 5.0, 0.75);
+        let (state, _) = run(&signal);
+        assert_eq!(state.counts, [3, 2]);
+        assert_eq!(state.misses, 0);
+    }
+
+    #[test]
+    fn every_detector_calibrates_and_counts() {
+        let mut signal = Signal::calibrated();
+        signal
+            .plap(HARD, 0.75)
+            .plap(HARD / 5.0, 0.75)
+            .plap(HARD, 0.75);
+
+        for (name, detector) in [
+            ("level", DetectorKind::Level),
+            ("spectral-flux", DetectorKind::SpectralFlux),
+            ("high-frequency-content", DetectorKind::HighFrequencyContent),
+            ("energy-derivative", DetectorKind::EnergyDerivative),
+        ] {
+            // No active delay, so that anything odd while a detector starts up shows
+            let settings = Settings {
+                detector,
+                active_delay_ms: 0.0,
+                ..Settings::default()
+            };
+            let (state, ticks) = run_with(&signal, settings);
+            assert!(
+                ticks.iter().any(|tick| matches!(tick, Tick::Calibrated(_))),
+                "{name} didn't calibrate"
+            );
+            assert!(
+                !ticks.iter().any(|tick| matches!(tick, Tick::Noise(_))),
+                "{name} took part of a quiet recording for noise"
+            );
+            assert_eq!(state.counts, [1, 2], "{name} miscounted");
+        }
+    }
+
+    #[test]
+    fn ignores_plaps_inside_the_refractory_period() {
+        let mut signal = Signal::calibrated();
+        signal.plap(HARD, 0.05).plap(HARD, 0.75);
+        let (state, _) = run(&signal);
+        assert_eq!(state.counts, [0, 1]);
+    }
+
+    #[test]
+    fn ignores_echoes() {
+        let mut signal = Signal::calibrated();
+        signal.plap(HARD, 0.2).plap(HARD / 10.0, 0.75);
+        let (state, _) = run(&signal);
+        assert_eq!(state.counts, [0, 1]);
+    }
+
+    #[test]
+    fn ignores_sustained_noise() {
+        let mut signal = Signal::calibrated();
+        signal.noise(0.1, 2.0).quiet(1.0).plap(HARD, 0.75);
+        let (state, ticks) = run(&signal);
+        let noise: Vec<_> = ticks
+            .iter()
+            .filter_map(|tick| match tick {
+                Tick::Noise(noise) => Some(*noise),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(noise[..], [Noise::TooLoud, Noise::Settled]));
+        assert_eq!(state.counts, [0, 1]);
+    }
+
+    #[test]
+    fn counts_misses() {
+        let mut signal = Signal::calibrated();
+        signal.plap(HARD / 16.0, 0.75).plap(HARD, 0.75);
+        let (state, ticks) = run(&signal);
+        assert_eq!(state.misses, 1);
+        assert_eq!(state.counts, [0, 1]);
+        assert!(ticks.iter().any(|tick| matches!(tick, Tick::Miss { .. })));
+    }
+}
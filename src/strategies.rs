@@ -0,0 +1,422 @@
+use crate::{detector::Detector, filter::FilterChain};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, f32::consts::PI, sync::Arc, time::Duration};
+
+/// Lowest level a detector reports, in dB, so that digital silence doesn't come out as minus
+/// infinity and take the noise floor with it.
+const MIN_LEVEL: f32 = -120.0;
+
+/// How many times over true peak measurement samples the signal.
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+/// Input samples each interpolated sample is worked out from.
+const TRUE_PEAK_TAPS: usize = 8;
+/// Offset ITU-R BS.1770 adds to K-weighted power so that a 1 kHz tone reads the same as
+/// unweighted, in dB.
+const K_WEIGHTING_OFFSET: f32 = -0.691;
+
+/// Shortest FFT window for spectral flux, rounded up to a power of two in samples.
+const FLUX_WINDOW: Duration = Duration::from_millis(20);
+/// How many spectral flux windows overlap each sample.
+const FLUX_OVERLAP: usize = 4;
+/// Scale applied to magnitudes before taking their logarithm, so that quiet bins still register
+/// but loud ones don't swamp the flux.
+const FLUX_COMPRESSION: f32 = 100.0;
+/// How much recent flux the adaptive threshold averages over.
+const FLUX_CONTEXT: Duration = Duration::from_millis(100);
+
+/// The onset detection functions to choose from.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DetectorKind {
+    /// Loudness of the frame, by the chosen loudness measure
+    #[serde(alias = "rms")]
+    Level,
+    /// How much the magnitude spectrum grew since the previous frame
+    SpectralFlux,
+    /// Frame energy weighted towards high frequencies
+    HighFrequencyContent,
+    /// How much louder the frame is than the previous one
+    EnergyDerivative,
+}
+
+impl DetectorKind {
+    /// Creates the detector for frames of `frame_length` samples. `loudness` is how the
+    /// level-based detectors measure each frame.
+    pub fn build(
+        self,
+        loudness: Loudness,
+        frame_length: usize,
+        sample_rate: u32,
+    ) -> Box<dyn Detector> {
+        let meter = || Meter::new(loudness, frame_length, sample_rate);
+        match self {
+            DetectorKind::Level => Box::new(Level { meter: meter() }),
+            DetectorKind::SpectralFlux => Box::new(SpectralFlux::new(sample_rate)),
+            DetectorKind::HighFrequencyContent => Box::new(HighFrequencyContent::new(frame_length)),
+            DetectorKind::EnergyDerivative => Box::new(EnergyDerivative {
+                meter: meter(),
+                previous: None,
+            }),
+        }
+    }
+}
+
+/// How loud a frame is taken to be.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Loudness {
+    /// Root mean square of the samples
+    Rms,
+    /// Largest sample
+    SamplePeak,
+    /// Largest value of the oversampled signal, which catches peaks that fall between samples
+    TruePeak,
+    /// Root mean square after A-weighting, which follows how loud quiet sounds seem
+    AWeighted,
+    /// Root mean square after K-weighting, as in ITU-R BS.1770 loudness
+    KWeighted,
+}
+
+/// Measures frames by a `Loudness`.
+///
+/// Weighting filters run over the stream rather than each frame, so that they don't ring at the
+/// start of every frame, and the weighted copy of the latest frame is kept alongside.
+pub struct Meter {
+    loudness: Loudness,
+    weighting: Option<FilterChain>,
+    /// Weighted samples of the latest frame.
+    weighted: Vec<f32>,
+    frame_length: usize,
+    /// Interpolation filter for each point between two samples, for true peak.
+    phases: Vec<[f32; TRUE_PEAK_TAPS]>,
+}
+
+impl Meter {
+    pub fn new(loudness: Loudness, frame_length: usize, sample_rate: u32) -> Self {
+        let weighting = match loudness {
+            Loudness::AWeighted => Some(FilterChain::a_weighting(sample_rate)),
+            Loudness::KWeighted => Some(FilterChain::k_weighting(sample_rate)),
+            _ => None,
+        };
+        let phases = match loudness {
+            Loudness::TruePeak => (1..TRUE_PEAK_OVERSAMPLING)
+                .map(|phase| interpolator(phase as f32 / TRUE_PEAK_OVERSAMPLING as f32))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Self {
+            loudness,
+            weighting,
+            weighted: Vec::with_capacity(frame_length * 2),
+            frame_length,
+            phases,
+        }
+    }
+
+    /// Level of `frame`, of which `new` hasn't been measured before.
+    pub fn measure(&mut self, frame: &[f32], new: &[f32]) -> f32 {
+        if let Some(weighting) = &mut self.weighting {
+            self.weighted
+                .extend(new.iter().map(|&sample| weighting.process(sample)));
+            let excess = self.weighted.len().saturating_sub(self.frame_length);
+            self.weighted.drain(..excess);
+        }
+
+        match self.loudness {
+            Loudness::Rms => power_db(mean_square(frame)),
+            Loudness::SamplePeak => power_db(peak(frame).powi(2)),
+            Loudness::TruePeak => power_db(self.true_peak(frame).powi(2)),
+            Loudness::AWeighted => power_db(mean_square(&self.weighted)),
+            Loudness::KWeighted => power_db(mean_square(&self.weighted)) + K_WEIGHTING_OFFSET,
+        }
+    }
+
+    /// The samples the level is measured on.
+    fn samples<'a>(&'a self, frame: &'a [f32]) -> &'a [f32] {
+        if self.weighting.is_some() {
+            &self.weighted
+        } else {
+            frame
+        }
+    }
+
+    /// Peak of `frame` oversampled by `TRUE_PEAK_OVERSAMPLING`. The interpolated points need
+    /// samples on both sides, so the edges of the frame are only checked at the samples.
+    fn true_peak(&self, frame: &[f32]) -> f32 {
+        let mut max = peak(frame);
+        for window in frame.windows(TRUE_PEAK_TAPS) {
+            for phase in &self.phases {
+                let sample: f32 = phase.iter().zip(window).map(|(c, x)| c * x).sum();
+                max = max.max(sample.abs());
+            }
+        }
+        max
+    }
+}
+
+/// Hann-windowed sinc filter for the point `fraction` of the way from the middle two of
+/// `TRUE_PEAK_TAPS` samples, scaled to unity gain.
+fn interpolator(fraction: f32) -> [f32; TRUE_PEAK_TAPS] {
+    let half = (TRUE_PEAK_TAPS / 2) as f32;
+    let mut taps = [0.0; TRUE_PEAK_TAPS];
+    for (i, tap) in taps.iter_mut().enumerate() {
+        // Distance from the interpolated point to this sample
+        let x = fraction + half - 1.0 - i as f32;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        *tap = sinc * (0.5 + 0.5 * (PI * x / half).cos());
+    }
+    let sum: f32 = taps.iter().sum();
+    taps.map(|tap| tap / sum)
+}
+
+fn power_db(power: f32) -> f32 {
+    if power > 0.0 {
+        (10.0 * power.log10()).max(MIN_LEVEL)
+    } else {
+        MIN_LEVEL
+    }
+}
+
+fn mean_square(frame: &[f32]) -> f32 {
+    frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32
+}
+
+fn peak(frame: &[f32]) -> f32 {
+    frame
+        .iter()
+        .fold(0.0f32, |max, sample| max.max(sample.abs()))
+}
+
+/// Level of the frame, the original detector.
+struct Level {
+    meter: Meter,
+}
+
+impl Detector for Level {
+    fn level(&mut self, frame: &[f32], new: &[f32]) -> Option<f32> {
+        Some(self.meter.measure(frame, new))
+    }
+
+    /// The first sample that reaches the peak threshold on its own.
+    fn onset_index(&self, frame: &[f32], threshold: f32) -> usize {
+        let threshold = 10f32.powf(threshold / 20.0);
+        self.meter
+            .samples(frame)
+            .iter()
+            .position(|sample| sample.abs() >= threshold)
+            .unwrap_or(0)
+    }
+}
+
+/// Change in level from one frame to the next. Steady sounds come out near 0 dB however loud they
+/// are, and only sudden rises stand out.
+struct EnergyDerivative {
+    meter: Meter,
+    previous: Option<f32>,
+}
+
+impl Detector for EnergyDerivative {
+    fn level(&mut self, frame: &[f32], new: &[f32]) -> Option<f32> {
+        let level = self.meter.measure(frame, new);
+        let previous = self.previous.replace(level).unwrap_or(level);
+        Some(level - previous)
+    }
+}
+
+/// Magnitude spectrum of Hann-windowed frames.
+struct Spectrum {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// Magnitudes of the bins from DC up to Nyquist, scaled by the frame length.
+    magnitudes: Vec<f32>,
+}
+
+impl Spectrum {
+    fn new(frame_length: usize) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(frame_length);
+        let window = (0..frame_length)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / frame_length as f32).cos())
+            .collect();
+        let scratch = vec![Complex::default(); fft.get_inplace_scratch_len()];
+
+        Self {
+            fft,
+            window,
+            buffer: vec![Complex::default(); frame_length],
+            scratch,
+            magnitudes: vec![0.0; frame_length / 2 + 1],
+        }
+    }
+
+    fn analyse(&mut self, frame: &[f32]) -> &[f32] {
+        for ((bin, sample), weight) in self.buffer.iter_mut().zip(frame).zip(&self.window) {
+            *bin = Complex::new(sample * weight, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        let scale = 1.0 / self.buffer.len() as f32;
+        for (magnitude, bin) in self.magnitudes.iter_mut().zip(&self.buffer) {
+            *magnitude = bin.norm() * scale;
+        }
+        &self.magnitudes
+    }
+}
+
+/// Increase in log-compressed magnitude across the frequency bins, compared with the flux over
+/// the last `FLUX_CONTEXT`. Plaps are broadband, so they raise nearly every bin at once, where
+/// speech or music only raise a few, and the adaptive threshold keeps busy stretches from
+/// triggering on every syllable.
+///
+/// Runs its own short, overlapping FFT windows over the stream rather than one per analysis
+/// frame, and reports the strongest flux among the windows completed since the last frame.
+struct SpectralFlux {
+    spectrum: Spectrum,
+    /// Samples not yet covered by a full window, oldest first.
+    samples: Vec<f32>,
+    window_length: usize,
+    window_hop: usize,
+    previous: Vec<f32>,
+    /// Flux of the most recent windows, oldest first.
+    recent: VecDeque<f32>,
+    context: usize,
+    /// Level of the last frame, held when a frame doesn't complete a window. `None` until the
+    /// first window is complete.
+    level: Option<f32>,
+}
+
+impl SpectralFlux {
+    fn new(sample_rate: u32) -> Self {
+        let window_length =
+            ((FLUX_WINDOW.as_secs_f32() * sample_rate as f32) as usize).next_power_of_two();
+        let window_hop = window_length / FLUX_OVERLAP;
+        let context =
+            ((FLUX_CONTEXT.as_secs_f32() * sample_rate as f32) as usize / window_hop).max(1);
+
+        Self {
+            spectrum: Spectrum::new(window_length),
+            samples: Vec::with_capacity(window_length * 2),
+            window_length,
+            window_hop,
+            previous: vec![0.0; window_length / 2 + 1],
+            recent: VecDeque::with_capacity(context),
+            context,
+            level: None,
+        }
+    }
+
+    /// Flux of the window at the start of `samples`, relative to the recent average.
+    fn window_level(&mut self) -> f32 {
+        let magnitudes = self.spectrum.analyse(&self.samples[..self.window_length]);
+        let mut flux = 0.0;
+        for (magnitude, previous) in magnitudes.iter().zip(&mut self.previous) {
+            let compressed = (1.0 + FLUX_COMPRESSION * magnitude).ln();
+            flux += (compressed - *previous).max(0.0);
+            *previous = compressed;
+        }
+        flux /= magnitudes.len() as f32;
+
+        let average = match self.recent.len() {
+            0 => flux,
+            n => self.recent.iter().sum::<f32>() / n as f32,
+        };
+        if self.recent.len() == self.context {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(flux);
+
+        if average > 0.0 {
+            power_db((flux / average).powi(2))
+        } else {
+            MIN_LEVEL
+        }
+    }
+}
+
+impl Detector for SpectralFlux {
+    fn level(&mut self, _frame: &[f32], new: &[f32]) -> Option<f32> {
+        self.samples.extend_from_slice(new);
+
+        let mut strongest = None;
+        while self.samples.len() >= self.window_length {
+            let level = self.window_level();
+            strongest = Some(strongest.map_or(level, |s: f32| s.max(level)));
+            self.samples.drain(..self.window_hop);
+        }
+        if strongest.is_some() {
+            self.level = strongest;
+        }
+        self.level
+    }
+}
+
+/// Spectral energy with each bin weighted by its frequency, which favours the sharp, bright
+/// attack of a plap over low thumps and hum.
+struct HighFrequencyContent {
+    spectrum: Spectrum,
+}
+
+impl HighFrequencyContent {
+    fn new(frame_length: usize) -> Self {
+        Self {
+            spectrum: Spectrum::new(frame_length),
+        }
+    }
+}
+
+impl Detector for HighFrequencyContent {
+    fn level(&mut self, frame: &[f32], _new: &[f32]) -> Option<f32> {
+        let magnitudes = self.spectrum.analyse(frame);
+        let hfc: f32 = magnitudes
+            .iter()
+            .enumerate()
+            .map(|(k, magnitude)| k as f32 * magnitude * magnitude)
+            .sum();
+        Some(power_db(hfc / magnitudes.len() as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolator_passes_samples_through_unchanged() {
+        let taps = interpolator(0.0);
+        let middle = TRUE_PEAK_TAPS / 2 - 1;
+        for (i, tap) in taps.iter().enumerate() {
+            let expected = if i == middle { 1.0 } else { 0.0 };
+            assert!((tap - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn interpolator_has_unity_gain() {
+        for phase in 1..TRUE_PEAK_OVERSAMPLING {
+            let taps = interpolator(phase as f32 / TRUE_PEAK_OVERSAMPLING as f32);
+            assert!((taps.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        // A quarter of the sample rate, sampled 45 degrees off its peaks, so every sample is
+        // 3 dB below the real peak
+        let frame: Vec<f32> = (0..480)
+            .map(|i| (PI / 2.0 * i as f32 + PI / 4.0).sin())
+            .collect();
+
+        let mut sample_peak = Meter::new(Loudness::SamplePeak, frame.len(), 48000);
+        let mut true_peak = Meter::new(Loudness::TruePeak, frame.len(), 48000);
+        assert!((sample_peak.measure(&frame, &frame) - -3.01).abs() < 0.05);
+        assert!(true_peak.measure(&frame, &frame).abs() < 0.5);
+    }
+}
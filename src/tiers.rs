@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+
+/// One band of plap intensity, as given in the config.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    pub name: String,
+    /// How far above the calibrated hard threshold a plap has to peak to reach this tier, in dB.
+    /// Left out for the lowest tier, which takes everything below the next one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_db: Option<f32>,
+    /// Shown when a plap lands in this tier.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Tier {
+    /// Name with its first letter capitalised, for output.
+    pub fn title(&self) -> String {
+        let mut chars = self.name.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        }
+    }
+}
+
+/// The original two outcomes: soft below the hard threshold, hard from it up.
+pub fn default_tiers() -> Vec<Tier> {
+    vec![
+        Tier {
+            name: "soft".to_owned(),
+            min_db: None,
+            message: Some("Worthless paypig!".to_owned()),
+        },
+        Tier {
+            name: "hard".to_owned(),
+            min_db: Some(0.0),
+            message: Some("Good girl~!".to_owned()),
+        },
+    ]
+}
+
+/// Checks that `tiers` start with one open-ended tier and go up from there without gaps or
+/// overlaps.
+pub fn validate(tiers: &[Tier]) -> anyhow::Result<()> {
+    let Some((lowest, rest)) = tiers.split_first() else {
+        return Err(anyhow::anyhow!("Tiers can't be empty"));
+    };
+    if lowest.min_db.is_some() {
+        return Err(anyhow::anyhow!(
+            "The lowest tier, \"{}\", can't have a min_db",
+            lowest.name
+        ));
+    }
+
+    let mut previous = f32::NEG_INFINITY;
+    for tier in rest {
+        let Some(min_db) = tier.min_db else {
+            return Err(anyhow::anyhow!(
+                "Tier \"{}\" needs a min_db, only the lowest tier can leave it out",
+                tier.name
+            ));
+        };
+        if min_db <= previous {
+            return Err(anyhow::anyhow!(
+                "Tiers have to go from quietest to loudest, \"{}\" starts at {min_db} dB",
+                tier.name
+            ));
+        }
+        previous = min_db;
+    }
+
+    for (i, tier) in tiers.iter().enumerate() {
+        if tier.name.is_empty() {
+            return Err(anyhow::anyhow!("Tier names can't be empty"));
+        }
+        if tiers[..i].iter().any(|other| other.name == tier.name) {
+            return Err(anyhow::anyhow!(
+                "There's more than one tier called \"{}\"",
+                tier.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Index of the tier a plap peaking `above_hard` dB over the hard threshold falls in.
+pub fn classify(tiers: &[Tier], above_hard: f32) -> usize {
+    tiers
+        .iter()
+        .rposition(|tier| tier.min_db.is_none_or(|min_db| above_hard >= min_db))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(name: &str, min_db: Option<f32>) -> Tier {
+        Tier {
+            name: name.to_owned(),
+            min_db,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn default_tiers_are_valid() {
+        assert!(validate(&default_tiers()).is_ok());
+    }
+
+    #[test]
+    fn rejects_badly_formed_tiers() {
+        assert!(validate(&[]).is_err());
+        assert!(validate(&[tier("soft", Some(0.0))]).is_err());
+        assert!(validate(&[tier("soft", None), tier("hard", None)]).is_err());
+        assert!(validate(&[
+            tier("soft", None),
+            tier("hard", Some(3.0)),
+            tier("max", Some(3.0))
+        ])
+        .is_err());
+        assert!(validate(&[tier("soft", None), tier("soft", Some(0.0))]).is_err());
+        assert!(validate(&[tier("", None)]).is_err());
+    }
+
+    #[test]
+    fn classifies_by_lowest_tier_reached() {
+        let tiers = [
+            tier("soft", None),
+            tier("hard", Some(0.0)),
+            tier("max", Some(6.0)),
+        ];
+        assert_eq!(classify(&tiers, -10.0), 0);
+        assert_eq!(classify(&tiers, 0.0), 1);
+        assert_eq!(classify(&tiers, 5.9), 1);
+        assert_eq!(classify(&tiers, 6.0), 2);
+        assert_eq!(classify(&tiers, 40.0), 2);
+    }
+}